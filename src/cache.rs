@@ -0,0 +1,405 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use itertools::Itertools;
+use rand::Rng;
+
+use crate::{
+    failure::Failure,
+    globals, log,
+    transaction::{Transaction, TxLogEntry},
+    utils,
+    virtual_system::VirtualSystem,
+};
+
+/// Returns the directories directly under `root` that satisfy `pred`, newest first.
+fn dirs_by_recency<F: Fn(&Path) -> bool>(root: &Path, pred: F) -> anyhow::Result<Vec<PathBuf>> {
+    if !root.is_dir() {
+        return Ok(vec![]);
+    }
+    let dirs = root
+        .read_dir()
+        .context(format!("could not read the directory {:?}", root))?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir() && pred(path))
+        .flat_map(|path| {
+            let modified = path.metadata().and_then(|m| m.modified()).ok()?;
+            Some((path, modified))
+        })
+        .sorted_by_key(|(_, modified)| *modified)
+        .rev()
+        .map(|(path, _)| path)
+        .collect_vec();
+    Ok(dirs)
+}
+
+/// Returns the cached transaction directories, newest first. They are nested under a directory per
+/// day, except the ones written by older versions, which are directly under the transactions directory.
+fn transaction_dirs() -> anyhow::Result<Vec<PathBuf>> {
+    let mut tx_dirs = vec![];
+    for dir in dirs_by_recency(&globals::transactions_dir(), |_| true)? {
+        if dir.join("tx").is_file() {
+            tx_dirs.push(dir);
+        } else {
+            tx_dirs.extend(dirs_by_recency(&dir, |_| true)?);
+        }
+    }
+    let tx_dirs = tx_dirs
+        .into_iter()
+        .flat_map(|path| {
+            let modified = path.metadata().and_then(|m| m.modified()).ok()?;
+            Some((path, modified))
+        })
+        .sorted_by_key(|(_, modified)| *modified)
+        .rev()
+        .map(|(path, _)| path)
+        .collect_vec();
+    Ok(tx_dirs)
+}
+
+/// Removes the given directory and returns the number of bytes reclaimed.
+fn remove_dir(path: &Path) -> anyhow::Result<u64> {
+    let size = utils::dir_size(path);
+    std::fs::remove_dir_all(path).context(format!("could not remove {:?}", path))?;
+    Ok(size)
+}
+
+/// Removes all the cached transactions. Succeeds if there are none.
+pub fn clear_transactions() -> anyhow::Result<()> {
+    let transactions_dir = globals::transactions_dir();
+    let num_txs = transaction_dirs()?.len();
+    if transactions_dir.exists() {
+        std::fs::remove_dir_all(&transactions_dir)
+            .context(format!("could not remove {:?}", transactions_dir))?;
+    }
+    log::info!("Cleared {} transactions", num_txs);
+    Ok(())
+}
+
+/// Returns the builds that are marked as deployed, newest first.
+pub fn deployed_builds() -> anyhow::Result<Vec<PathBuf>> {
+    let builds_dir = globals::builds_dir();
+    let builds = dirs_by_recency(&builds_dir, |p| p.join(globals::BUILD_FILE_NAME).is_file())?;
+    Ok(builds
+        .into_iter()
+        .filter(|build| VirtualSystem::read(build.to_path_buf()).is_ok_and(|vs| vs.is_deployed()))
+        .collect_vec())
+}
+
+/// Returns the build that is currently deployed, if any.
+pub fn deployed_build() -> anyhow::Result<Option<PathBuf>> {
+    Ok(deployed_builds()?.into_iter().next())
+}
+
+/// Removes all the builds. Succeeds if there are none, and fails if one of them is deployed.
+pub fn clear_builds() -> anyhow::Result<()> {
+    let builds_dir = globals::builds_dir();
+    let builds = dirs_by_recency(&builds_dir, |p| p.join(globals::BUILD_FILE_NAME).is_file())?;
+    if let Some(deployed_build) = builds
+        .iter()
+        .find(|build| VirtualSystem::read(build.to_path_buf()).is_ok_and(|vs| vs.is_deployed()))
+    {
+        return Err(anyhow::anyhow!(
+            "the build {:?} is deployed, undeploy it first with `dull undeploy --build {:?}`",
+            deployed_build,
+            deployed_build
+        ))
+        .context(Failure::Precondition);
+    }
+    if builds_dir.exists() {
+        std::fs::remove_dir_all(&builds_dir)
+            .context(format!("could not remove {:?}", builds_dir))?;
+    }
+    log::info!("Cleared {} builds", builds.len());
+    Ok(())
+}
+
+/// Removes all but the newest `keep` builds and transactions. The deployed builds, the build referenced
+/// by the state and the transactions that deployed them are never removed, nor are the transactions
+/// that undo the kept builds.
+pub fn prune(keep: usize) -> anyhow::Result<()> {
+    let state_build = utils::get_state().ok().map(PathBuf::from);
+    let protected_builds = deployed_builds()?
+        .into_iter()
+        .chain(state_build)
+        .flat_map(|build| utils::expand_path(&build))
+        .collect_vec();
+    let mut reclaimed = 0;
+    let builds = dirs_by_recency(&globals::builds_dir(), |p| {
+        p.join(globals::BUILD_FILE_NAME).is_file()
+    })?;
+    let mut protected_txs = vec![];
+    for (i, build) in builds.into_iter().enumerate() {
+        let is_protected = utils::expand_path(&build).is_ok_and(|p| protected_builds.contains(&p));
+        if i >= keep && !is_protected {
+            reclaimed += remove_dir(&build)?;
+            log::info!("Removed build {:?}", build);
+            continue;
+        }
+        if i >= keep {
+            log::info!(
+                "Keeping the build {:?}, which is deployed or referenced by the state",
+                build
+            );
+        }
+        if let Ok(virt_system) = VirtualSystem::read(build) {
+            if is_protected {
+                protected_txs.extend(virt_system.transaction_dirs());
+            }
+            protected_txs.extend(virt_system.undo_transaction_dir());
+        }
+    }
+    let txs = transaction_dirs()?;
+    for tx in txs.into_iter().skip(keep) {
+        let is_protected = utils::expand_path(&tx).is_ok_and(|p| protected_txs.contains(&p));
+        if is_protected {
+            log::info!("Keeping the transaction {:?} required by a kept build", tx);
+            continue;
+        }
+        reclaimed += remove_dir(&tx)?;
+        log::info!("Removed transaction {:?}", tx);
+        // Remove the directory of the day along with its last transaction.
+        if let Some(day_dir) = tx.parent().filter(|p| *p != globals::transactions_dir()) {
+            if day_dir.read_dir().is_ok_and(|mut d| d.next().is_none()) {
+                std::fs::remove_dir(day_dir).context(format!("could not remove {:?}", day_dir))?;
+            }
+        }
+    }
+    log::info!("Reclaimed {} bytes", reclaimed);
+    Ok(())
+}
+
+/// Lists the cached transactions newest first, optionally only those written within `since`.
+pub fn history(since: Option<std::time::Duration>) -> anyhow::Result<()> {
+    let log_entries: HashMap<String, TxLogEntry> = TxLogEntry::read_all()?
+        .into_iter()
+        .map(|entry| (entry.id.clone(), entry))
+        .collect();
+    let cutoff = since.and_then(|since| std::time::SystemTime::now().checked_sub(since));
+    let txs = transaction_dirs()?
+        .into_iter()
+        .flat_map(|tx_dir| {
+            let tx_file_path = tx_dir.join("tx");
+            let modified = tx_file_path.metadata().and_then(|m| m.modified()).ok()?;
+            Some((tx_file_path, modified))
+        })
+        .filter(|(_, modified)| cutoff.is_none_or(|cutoff| *modified >= cutoff))
+        .sorted_by_key(|(_, modified)| *modified)
+        .rev()
+        .collect_vec();
+    for (tx_file_path, modified) in txs.iter() {
+        let tx = match Transaction::read(tx_file_path.clone()) {
+            Ok(tx) => tx,
+            Err(err) => {
+                log::warning!(
+                    "Skipping the unreadable transaction {:?}: {:?}",
+                    tx_file_path,
+                    err
+                );
+                continue;
+            }
+        };
+        let (time, status) = match log_entries.get(tx.id()) {
+            Some(entry) => (
+                utils::format_timestamp(entry.timestamp),
+                if entry.success { "" } else { " (failed)" },
+            ),
+            // Undo transactions are not logged until they are run, fall back to the file time.
+            None => (
+                utils::format_timestamp(
+                    modified
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .map(|d| d.as_secs())
+                        .unwrap_or_default(),
+                ),
+                "",
+            ),
+        };
+        log::info!(
+            "{}  {}  {} ({} primitives){}",
+            time,
+            tx.name(),
+            tx.id(),
+            tx.num_primitives(),
+            status
+        );
+    }
+    if txs.is_empty() {
+        log::info!("No transactions found");
+    }
+    Ok(())
+}
+
+/// Checks that the backups required by the cached transactions are intact and reports the transactions
+/// that can no longer be executed.
+pub fn verify() -> anyhow::Result<()> {
+    let transactions_root = globals::transactions_dir();
+    let txs = transaction_dirs()?;
+    let mut num_broken = 0;
+    for tx_dir in txs.iter() {
+        let tx_file_path = tx_dir.join("tx");
+        let tx = match Transaction::read(tx_file_path) {
+            Ok(tx) => tx,
+            Err(err) => {
+                num_broken += 1;
+                log::error!(" ✗ {:?} is unreadable: {:?}", tx_dir, err);
+                continue;
+            }
+        };
+        let missing_backups = tx.missing_backups(&transactions_root);
+        if missing_backups.is_empty() {
+            continue;
+        }
+        num_broken += 1;
+        log::error!(" ✗ {:?} cannot be executed, missing backups:", tx_dir);
+        for backup in missing_backups {
+            log::error!("   - {:?}", backup);
+        }
+    }
+    if num_broken == 0 {
+        log::info!(" ✓ All {} transactions are intact", txs.len());
+    } else {
+        log::error!(" ✗ {}/{} transactions are broken", num_broken, txs.len());
+    }
+    Ok(())
+}
+
+/// Runs `tar` with the given arguments.
+fn run_tar(args: &[&std::ffi::OsStr]) -> anyhow::Result<()> {
+    let output = std::process::Command::new("tar")
+        .args(args)
+        .output()
+        .context("could not run tar")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "tar failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Packs the build at the given path into a gzipped tar archive. The symlinks of the build are kept as
+/// they are, i.e., pointing to the absolute paths of the module sources. The deployment records of the
+/// build are left out, as they only concern this machine.
+pub fn pack_build(build_path: &Path, archive_path: &Path) -> anyhow::Result<()> {
+    let build_path = utils::expand_path(&build_path.to_path_buf())?;
+    let (Some(build_root), Some(build_name)) = (build_path.parent(), build_path.file_name()) else {
+        anyhow::bail!("{:?} is not a build", build_path);
+    };
+    let exclude_deployed = format!("--exclude={}", globals::DEPLOYED_FILE_NAME);
+    let exclude_transactions = format!("--exclude={}", globals::TRANSACTIONS_FILE_NAME);
+    run_tar(&[
+        exclude_deployed.as_ref(),
+        exclude_transactions.as_ref(),
+        "-czf".as_ref(),
+        archive_path.as_os_str(),
+        "-C".as_ref(),
+        build_root.as_os_str(),
+        build_name,
+    ])
+    .context(format!(
+        "could not pack the build {:?} into {:?}",
+        build_path, archive_path
+    ))
+}
+
+/// Unpacks the build in the given archive into the builds directory and returns its path. Fails if a
+/// build with the same name already exists.
+pub fn unpack_build(archive_path: &Path) -> anyhow::Result<PathBuf> {
+    let builds_dir = globals::builds_dir();
+    let unpack_dir =
+        globals::data_dir().join(format!(".dull-unpack-{}", rand::thread_rng().gen::<u32>()));
+    std::fs::create_dir_all(&unpack_dir)
+        .context(format!("could not create the directory {:?}", unpack_dir))?;
+    let result = run_tar(&[
+        "-xzf".as_ref(),
+        archive_path.as_os_str(),
+        "-C".as_ref(),
+        unpack_dir.as_os_str(),
+    ])
+    .context(format!("could not unpack the archive {:?}", archive_path))
+    .and_then(|_| {
+        let entries = unpack_dir
+            .read_dir()
+            .context(format!("could not read the directory {:?}", unpack_dir))?
+            .flatten()
+            .map(|entry| entry.path())
+            .collect_vec();
+        let [unpacked_build] = entries.as_slice() else {
+            anyhow::bail!("{:?} does not contain a single build", archive_path);
+        };
+        if !unpacked_build.join(globals::BUILD_FILE_NAME).is_file() {
+            anyhow::bail!("{:?} does not contain a build", archive_path);
+        }
+        let build_path = builds_dir.join(unpacked_build.file_name().unwrap_or_default());
+        if build_path.symlink_metadata().is_ok() {
+            return Err(anyhow::anyhow!(
+                "build {:?} already exists, remove it first",
+                build_path
+            ))
+            .context(Failure::Precondition);
+        }
+        std::fs::create_dir_all(&builds_dir)
+            .context(format!("could not create the directory {:?}", builds_dir))?;
+        std::fs::rename(unpacked_build, &build_path).context(format!(
+            "could not move the unpacked build into {:?}",
+            build_path
+        ))?;
+        Ok(build_path)
+    });
+    if let Err(err) = std::fs::remove_dir_all(&unpack_dir) {
+        log::warning!("Could not remove {:?}: {:?}", unpack_dir, err);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config_parser::{DeployMode, ResolvedConfig},
+        transaction::{DisplayMode, TxProcessor},
+        virtual_system::VirtualSystemBuilder,
+    };
+
+    #[test]
+    fn prunes_around_the_deployed_build() {
+        let dir = utils::test_dir();
+        std::fs::create_dir(dir.join("module")).unwrap();
+        std::fs::write(dir.join("module/config"), "").unwrap();
+        let config = ResolvedConfig::single_module(dir.join("module"), dir.join("target"));
+        let build = |name: &str| {
+            let build_path = VirtualSystemBuilder::from_config(&config)
+                .build(Some(name.to_string()), false, DisplayMode::Silent)
+                .unwrap();
+            utils::set_state(&build_path).unwrap();
+            VirtualSystem::read(build_path).unwrap()
+        };
+        let first = build("first");
+        let first_path = first.path.clone();
+        let mut tx_proc = TxProcessor::new("deployment", DisplayMode::Silent);
+        let first = first.prepare_deployment(&mut tx_proc).unwrap();
+        let ignore_filenames = first.ignore_filenames();
+        first
+            .deploy(DeployMode::Soft, &ignore_filenames, &mut tx_proc)
+            .unwrap();
+        let first = VirtualSystem::read(first_path).unwrap();
+        let second = build("second");
+        prune(0).unwrap();
+        // The deployed build is kept along with its transactions, and the latest one as the state.
+        assert!(first.path.is_dir() && second.path.is_dir());
+        let kept_txs = first
+            .transaction_dirs()
+            .into_iter()
+            .chain(first.undo_transaction_dir())
+            .chain(second.undo_transaction_dir())
+            .collect_vec();
+        assert!(kept_txs.len() > 2);
+        assert!(kept_txs.iter().all(|tx| tx.join("tx").is_file()));
+    }
+}
@@ -1,49 +1,462 @@
-use std::path::PathBuf;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
 use anyhow::Context;
+use itertools::Itertools;
+
+use crate::{globals, log, utils};
 
 #[derive(Clone, Debug, Default, serde::Deserialize)]
-#[serde(default)]
+#[serde(default, deny_unknown_fields)]
 pub struct IncludeConfig {
     pub path: PathBuf,
 }
 
-#[derive(Clone, Debug, Default, serde::Deserialize)]
-#[serde(default)]
+/// Denotes how the files of a module are deployed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeployMode {
+    /// Link the files to the module.
+    #[serde(alias = "symlink")]
+    Soft,
+    /// Copy the files from the module.
+    #[serde(alias = "copy")]
+    Hard,
+    /// Hard link the regular files to the module, falling back to copying across filesystems.
+    HardLink,
+}
+
+/// Denotes how much of the path of a file in a module is stripped before it is joined with the target.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PrefixStrip {
+    /// Strip the module source, so that its contents are linked under the target.
+    #[default]
+    Source,
+    /// Strip the parent of the module source, so that its contents are linked under
+    /// `<target>/<source directory name>`.
+    Parent,
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct ModuleConfig {
+    /// When unspecified, the module is named after its source directory.
+    pub name: Option<String>,
     pub source: PathBuf,
     pub target: PathBuf,
     pub linkthis: Vec<PathBuf>,
     pub linkthese: Vec<PathBuf>,
+    /// The directories whose files are linked individually at any depth, ignoring the nested directives.
+    pub linkthese_recursive: Vec<PathBuf>,
+    /// When non-empty, the module is only used on the machines with these hostnames.
+    pub hosts: Vec<String>,
+    /// When unspecified, the module is deployed in the mode chosen during the deployment.
+    #[serde(alias = "link_strategy")]
+    pub mode: Option<DeployMode>,
+    /// If set, the directive files under the symlinked directories of the module are also read.
+    pub follow_links: bool,
+    /// Whether the source directory name itself is kept in the targets.
+    pub prefix_strip: PrefixStrip,
+    /// Maps the glob patterns of the paths relative to the source to the permission bits of their targets.
+    /// Only applied to the targets that are copied, as the links share the mode of their sources.
+    pub permissions: HashMap<String, u32>,
+    /// Maps the paths relative to the module target to the names they are deployed with, e.g., to add
+    /// a leading dot. A renamed directory carries its contents along.
+    pub rename: HashMap<PathBuf, PathBuf>,
+    /// The configuration file that defines the module, set while the configuration is resolved.
+    #[serde(skip)]
+    pub origin: PathBuf,
+}
+
+impl ModuleConfig {
+    /// Returns the name of the module, falling back to the name of its source directory.
+    pub fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| {
+            self.source
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default()
+        })
+    }
+
+    /// Describes the module by its source and the configuration file that defines it, for the reports.
+    pub fn describe(&self) -> String {
+        format!("{:?} (defined in {:?})", self.source, self.origin)
+    }
+
+    /// Returns the problems of the module that can be found without parsing it, e.g., a missing source.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        match self.source.metadata() {
+            Ok(metadata) if metadata.is_dir() => {}
+            Ok(_) => problems.push("the source is not a directory".to_string()),
+            Err(_) => problems.push("the source does not exist".to_string()),
+        }
+        match utils::expand_target(&self.target) {
+            Ok(target) => {
+                let managed_dirs = [globals::builds_dir(), globals::transactions_dir()];
+                let is_managed = managed_dirs
+                    .iter()
+                    .any(|dir| utils::expand_path(dir).is_ok_and(|dir| target.starts_with(dir)));
+                if is_managed {
+                    problems.push(format!("the target {:?} is managed by dull", target));
+                }
+            }
+            Err(err) => problems.push(format!("invalid target: {:#}", err)),
+        }
+        let directives = self
+            .linkthis
+            .iter()
+            .chain(self.linkthese.iter())
+            .chain(self.linkthese_recursive.iter());
+        for directive in directives {
+            if let Err(err) = glob::Pattern::new(&directive.to_string_lossy()) {
+                problems.push(format!(
+                    "invalid directive pattern {:?}: {}",
+                    directive, err
+                ));
+            }
+        }
+        for (pattern_str, mode) in self.permissions.iter().sorted() {
+            if *mode > 0o7777 {
+                problems.push(format!(
+                    "invalid permission bits {:o} for {:?}",
+                    mode, pattern_str
+                ));
+            }
+            if let Err(err) = glob::Pattern::new(pattern_str) {
+                problems.push(format!(
+                    "invalid permission pattern {:?}: {}",
+                    pattern_str, err
+                ));
+            }
+        }
+        for (from, to) in self.rename.iter().sorted() {
+            let is_relative = |path: &Path| {
+                !path.as_os_str().is_empty()
+                    && path
+                        .components()
+                        .all(|component| matches!(component, std::path::Component::Normal(_)))
+            };
+            if !is_relative(from) || !is_relative(to) {
+                problems.push(format!(
+                    "invalid rename {:?} => {:?}, both paths must be relative without `..`",
+                    from, to
+                ));
+            }
+        }
+        problems
+    }
+
+    /// Compiles the permission patterns of the module, to be matched against all of its sources.
+    pub fn permission_patterns(&self) -> anyhow::Result<PermissionPatterns> {
+        let mut patterns = vec![];
+        for (pattern_str, mode) in self.permissions.iter() {
+            if *mode > 0o7777 {
+                anyhow::bail!("invalid permission bits {:o} for {:?}", mode, pattern_str);
+            }
+            let pattern = glob::Pattern::new(pattern_str)
+                .context(format!("invalid permission pattern {:?}", pattern_str))?;
+            patterns.push((pattern, *mode));
+        }
+        // The longest pattern takes precedence, so it is matched first.
+        patterns.sort_by(|(a, _), (b, _)| {
+            b.as_str()
+                .len()
+                .cmp(&a.as_str().len())
+                .then_with(|| a.as_str().cmp(b.as_str()))
+        });
+        Ok(PermissionPatterns {
+            module_path: utils::expand_path(&self.source)?,
+            patterns,
+        })
+    }
+
+    /// Returns true iff this module should be used on the machine with the given hostname.
+    pub fn applies_to_host(&self, hostname: &str) -> bool {
+        self.hosts.is_empty() || self.hosts.iter().any(|host| host == hostname)
+    }
+}
+
+/// The compiled permission patterns of a module.
+#[derive(Debug)]
+pub struct PermissionPatterns {
+    module_path: PathBuf,
+    /// The patterns along with their permission bits, ordered from the longest pattern.
+    patterns: Vec<(glob::Pattern, u32)>,
+}
+
+impl PermissionPatterns {
+    /// Returns the permission bits that should be applied to the target of the given source path.
+    /// If multiple patterns match, the longest one is used.
+    pub fn of(&self, abs_source: &Path) -> Option<u32> {
+        let relative_source = abs_source.strip_prefix(&self.module_path).ok()?;
+        let options = glob::MatchOptions {
+            require_literal_separator: true,
+            ..Default::default()
+        };
+        self.patterns
+            .iter()
+            .find(|(pattern, _)| pattern.matches_path_with(relative_source, options))
+            .map(|(_, mode)| *mode)
+    }
+}
+
+/// The settings that apply to all the modules, given under the `[global]` table.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GlobalConfig {
+    /// The name of the marker file that links its directory directly.
+    pub linkthis_file: String,
+    /// The name of the marker file that links the contents of its directory directly.
+    pub linkthese_file: String,
+    /// The name of the marker file that links the files at any depth under its directory individually.
+    pub linkthese_recursive_file: String,
+    /// Maps the target directories to the permission bits of the directories created under them.
+    pub dir_permissions: HashMap<PathBuf, u32>,
+}
+
+impl Default for GlobalConfig {
+    fn default() -> Self {
+        Self {
+            linkthis_file: globals::LINKTHIS_FILENAME.to_string(),
+            linkthese_file: globals::LINKTHESE_FILENAME.to_string(),
+            linkthese_recursive_file: globals::LINKTHESE_RECURSIVE_FILENAME.to_string(),
+            dir_permissions: Default::default(),
+        }
+    }
+}
+
+impl GlobalConfig {
+    /// Returns the permission bits of the directories created under the expanded targets.
+    pub fn dir_modes(&self) -> anyhow::Result<HashMap<PathBuf, u32>> {
+        let mut dir_modes = HashMap::new();
+        for (target, mode) in self.dir_permissions.iter() {
+            if *mode > 0o7777 {
+                anyhow::bail!("invalid permission bits {:o} for {:?}", mode, target);
+            }
+            dir_modes.insert(utils::expand_target(target)?, *mode);
+        }
+        Ok(dir_modes)
+    }
+
+    /// Returns the names of the marker files.
+    pub fn marker_filenames(&self) -> [&str; 3] {
+        [
+            &self.linkthis_file,
+            &self.linkthese_file,
+            &self.linkthese_recursive_file,
+        ]
+    }
+}
+
+/// The defaults of the deployment flags, given under the `[settings]` table. They are recorded in the
+/// builds, so that they apply when the build is deployed.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// Only display the effects of the deployments, unless `--no-dry-run` is given.
+    pub dry_run: bool,
+    /// Whether to ask for confirmation before the forced deployments remove the targets.
+    pub confirm: bool,
+    /// The mode of the modules that do not specify one, unless the mode is given on the command line.
+    pub default_mode: DeployMode,
+    /// Whether to back up the removed targets, unless `--no-backup` or `--backup` is given.
+    pub backup: bool,
+    /// Whether to link the soft deployed files through relative paths, as with `--relative-links`.
+    pub relative_links: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            dry_run: false,
+            confirm: true,
+            default_mode: DeployMode::Soft,
+            backup: true,
+            relative_links: false,
+        }
+    }
+}
+
+/// Denotes the order in which the modules of a configuration and its inclusions are merged.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IncludeOrder {
+    /// The modules of the included configurations come first, in the order of the includes.
+    #[default]
+    IncludesFirst,
+    /// The modules of the configuration itself come first, followed by the included ones.
+    RootFirst,
 }
 
 #[derive(Clone, Debug, Default, serde::Deserialize)]
-#[serde(default)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
+    /// Only read from the configuration file that is built, not from the included ones.
+    pub global: GlobalConfig,
+    /// Only read from the configuration file that is built, not from the included ones.
+    pub settings: Settings,
     pub include: Vec<IncludeConfig>,
+    /// Only affects the inclusions of this configuration file, not the nested ones.
+    pub include_order: IncludeOrder,
     pub module: Vec<ModuleConfig>,
+    pub ignore: Vec<String>,
 }
 
-#[derive(Clone, Debug, Default)]
+/// The configuration with all of its inclusions, serialized in the format of a configuration file.
+#[derive(Clone, Debug, Default, serde::Serialize)]
 pub struct ResolvedConfig {
+    pub ignore: Vec<String>,
+    pub global: GlobalConfig,
+    pub settings: Settings,
+    #[serde(rename = "module", skip_serializing_if = "Vec::is_empty")]
     pub modules: Vec<ModuleConfig>,
 }
 
 impl ResolvedConfig {
-    fn root(config: Config) -> Self {
+    /// Returns the configuration with the single module that links the contents of `source` under
+    /// `target` with the default settings, for the builds without a configuration file.
+    pub fn single_module(source: PathBuf, target: PathBuf) -> Self {
         ResolvedConfig {
-            modules: config.module,
+            modules: vec![ModuleConfig {
+                source,
+                target,
+                origin: PathBuf::from("the command line"),
+                ..Default::default()
+            }],
+            ..Default::default()
         }
     }
-    /// Merges this configuration with the given `parent_config` and returns the result.
-    fn merged(mut self, mut parent_config: ResolvedConfig) -> Self {
-        self.modules.extend(parent_config.modules.drain(..));
+
+    /// Resolves the modules of the configuration defined in the file at `origin`, without its includes.
+    fn root(config: Config, origin: &Path) -> Self {
+        let hostname = gethostname::gethostname().to_string_lossy().to_string();
+        ResolvedConfig {
+            global: config.global,
+            settings: config.settings,
+            modules: config
+                .module
+                .into_iter()
+                .filter(|module| module.applies_to_host(&hostname))
+                .map(|module| ModuleConfig {
+                    origin: origin.to_path_buf(),
+                    ..module
+                })
+                .collect_vec(),
+            ignore: config.ignore,
+        }
+    }
+
+    /// Merges this configuration with `other` by appending the modules of `other` after the
+    /// modules of this configuration, and returns the result.
+    fn merged(mut self, mut other: ResolvedConfig) -> Self {
+        self.modules.append(&mut other.modules);
+        self.ignore.append(&mut other.ignore);
         self
     }
+
+    /// Returns an error listing all the problems of the modules and the global settings that can be
+    /// found without parsing the modules, e.g., the sources that are not existing directories.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let mut problems = self
+            .modules
+            .iter()
+            .flat_map(|module| {
+                module
+                    .problems()
+                    .into_iter()
+                    .map(|problem| format!(" - {}: {}", module.describe(), problem))
+            })
+            .collect_vec();
+        if let Err(err) = self.global.dir_modes() {
+            problems.push(format!(" - [global]: {:#}", err));
+        }
+        if problems.is_empty() {
+            return Ok(());
+        }
+        anyhow::bail!(
+            "found {} problems:\n{}",
+            problems.len(),
+            problems.join("\n")
+        )
+    }
+
+    /// Returns the configuration in the format of a configuration file, noting the file that defines
+    /// each module in a comment.
+    pub fn to_toml(&self) -> anyhow::Result<String> {
+        #[derive(serde::Serialize)]
+        struct ModuleTable<'a> {
+            module: [&'a ModuleConfig; 1],
+        }
+        let mut output = toml::to_string(&ResolvedConfig {
+            modules: vec![],
+            ..self.clone()
+        })?;
+        for module in self.modules.iter() {
+            output.push_str(&format!("\n# Defined in {:?}\n", module.origin));
+            output.push_str(&toml::to_string(&ModuleTable { module: [module] })?);
+        }
+        Ok(output)
+    }
+
+    /// Returns the configuration in JSON, including the file that defines each module.
+    pub fn to_json(&self) -> anyhow::Result<serde_json::Value> {
+        let mut value = serde_json::to_value(self)?;
+        if let Some(module_values) = value["module"].as_array_mut() {
+            for (module_value, module) in module_values.iter_mut().zip(self.modules.iter()) {
+                module_value["origin"] = serde_json::json!(module.origin);
+            }
+        }
+        Ok(value)
+    }
+
+    /// Returns the filenames that should never be linked, including the default ones.
+    pub fn ignore_filenames(&self) -> Vec<String> {
+        utils::ignore_filenames(&self.global.marker_filenames())
+            .into_iter()
+            .chain(self.ignore.iter().cloned())
+            .unique()
+            .collect_vec()
+    }
+}
+
+/// Resolves the configuration files pointed by an include path. A path with glob patterns expands to all
+/// the matching paths, and a directory denotes the default configuration file inside it.
+fn resolve_include_path(path: &PathBuf) -> anyhow::Result<Vec<PathBuf>> {
+    let path_str = path.to_string_lossy();
+    let paths = if path_str.contains(['*', '?', '[']) {
+        glob::glob(&path_str)
+            .context(format!("invalid include pattern {:?}", path))?
+            .flatten()
+            .collect_vec()
+    } else {
+        vec![path.clone()]
+    };
+    let config_paths = paths
+        .into_iter()
+        .map(|p| {
+            if p.is_dir() {
+                p.join(globals::DEFAULT_CONFIG_FILE_NAME)
+            } else {
+                p
+            }
+        })
+        .collect_vec();
+    Ok(config_paths)
 }
 
+/// Reads and resolves the configuration file along with its inclusions recursively, without parsing
+/// the modules.
 pub fn read_config<P: Into<PathBuf>>(p: P) -> anyhow::Result<ResolvedConfig> {
-    let config_file_path = p.into();
+    read_config_file(&p.into())
+}
+
+fn read_config_file(config_file_path: &Path) -> anyhow::Result<ResolvedConfig> {
+    let config_file_path = config_file_path.to_path_buf();
     let config: Config = std::fs::read_to_string(&config_file_path)
         .context(format!("could not read config file {:?}", config_file_path))
         .and_then(|file_contents| {
@@ -55,23 +468,118 @@ pub fn read_config<P: Into<PathBuf>>(p: P) -> anyhow::Result<ResolvedConfig> {
     let inclusions = config
         .include
         .iter()
-        .map(|include_config| (read_config(&include_config.path), &include_config.path))
-        .flat_map(|(result, target_path)| {
-            match &result {
+        .flat_map(
+            |include_config| match resolve_include_path(&include_config.path) {
+                Ok(paths) => paths,
                 Err(err) => {
-                    println!(
+                    log::warning!(
                         "Skipping including {:?} from {:?} due to error: {:?}",
-                        target_path, config_file_path, err
+                        include_config.path,
+                        config_file_path,
+                        err
                     );
+                    vec![]
                 }
-                Ok(_) => {}
-            };
+            },
+        )
+        .map(|target_path| (read_config_file(&target_path), target_path))
+        .flat_map(|(result, target_path)| {
+            if let Err(err) = &result {
+                log::warning!(
+                    "Skipping including {:?} from {:?} due to error: {:?}",
+                    target_path,
+                    config_file_path,
+                    err
+                );
+            }
             result
         })
-        .reduce(|acc, e| acc.merged(e));
-    let parent = ResolvedConfig::root(config);
-    match inclusions {
-        Some(inclusions) => Ok(inclusions.merged(parent)),
-        None => Ok(parent),
+        .collect_vec();
+    // The inclusions are merged in the order they are included, either before or after the parent.
+    let include_order = config.include_order;
+    let global = config.global.clone();
+    let settings = config.settings.clone();
+    let parent = ResolvedConfig::root(config, &config_file_path);
+    let ordered = match include_order {
+        IncludeOrder::IncludesFirst => inclusions
+            .into_iter()
+            .chain(std::iter::once(parent))
+            .collect_vec(),
+        IncludeOrder::RootFirst => std::iter::once(parent).chain(inclusions).collect_vec(),
+    };
+    let resolved = ordered
+        .into_iter()
+        .fold(ResolvedConfig::default(), |acc, e| acc.merged(e));
+    Ok(ResolvedConfig {
+        global,
+        settings,
+        ..resolved
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_the_nested_inclusions() {
+        let dir = utils::test_dir();
+        let write = |name: &str, contents: String| {
+            std::fs::write(dir.join(name), contents).unwrap();
+        };
+        let module = |name: &str| {
+            format!(
+                "[[module]]\nsource = {:?}\ntarget = {:?}\n",
+                dir.join(name),
+                dir.join("target").join(name)
+            )
+        };
+        // The root includes `middle` first, which includes `leaf` after its own modules.
+        write(
+            "config.toml",
+            format!(
+                "[[include]]\npath = {:?}\n{}{}",
+                dir.join("middle.toml"),
+                module("root1"),
+                module("root2")
+            ),
+        );
+        write(
+            "middle.toml",
+            format!(
+                "include_order = \"root-first\"\n[[include]]\npath = {:?}\n{}",
+                dir.join("leaf.toml"),
+                module("middle")
+            ),
+        );
+        write(
+            "leaf.toml",
+            format!("{}{}", module("leaf1"), module("leaf2")),
+        );
+        let config = read_config(dir.join("config.toml")).unwrap();
+        let sources = config
+            .modules
+            .iter()
+            .map(|module| module.source.file_name().unwrap().to_string_lossy())
+            .collect_vec();
+        assert_eq!(sources, ["middle", "leaf1", "leaf2", "root1", "root2"]);
+        assert_eq!(config.modules[0].origin, dir.join("middle.toml"));
+        assert_eq!(config.modules[1].origin, dir.join("leaf.toml"));
+    }
+
+    #[test]
+    fn shows_a_parseable_config() {
+        let dir = utils::test_dir();
+        let config_path = dir.join("config.toml");
+        std::fs::write(
+            &config_path,
+            "[[module]]\nsource = \"nvim\"\ntarget = \".config/nvim\"\n[module.permissions]\n\"*.sh\" = 0o755\n",
+        )
+        .unwrap();
+        let shown = read_config(&config_path).unwrap().to_toml().unwrap();
+        assert!(shown.contains(&format!("# Defined in {:?}", config_path)));
+        let config: Config = toml::from_str(&shown).unwrap();
+        assert_eq!(config.module.len(), 1);
+        assert_eq!(config.module[0].permissions.get("*.sh"), Some(&0o755));
     }
 }
@@ -0,0 +1,46 @@
+use std::path::PathBuf;
+
+/// Denotes the failure modes that callers may want to tell apart. The errors are still returned as
+/// `anyhow::Error`, possibly with more context, and can be matched through `anyhow::Error::downcast_ref`.
+#[derive(Debug, thiserror::Error)]
+pub enum DullError {
+    /// A path that is about to be created already exists.
+    #[error("target {0:?} already exists")]
+    TargetExists(PathBuf),
+    /// A path that is about to be used or removed does not exist.
+    #[error("{0:?} does not exist")]
+    SourceMissing(PathBuf),
+    /// A path that should be a directory, e.g., a module source, is not one.
+    #[error("{0:?} is not a directory")]
+    NotADirectory(PathBuf),
+    /// A leaf of a build does not link to a source.
+    #[error("the leaf {0:?} is broken")]
+    BrokenLeaf(PathBuf),
+    /// A build with the same name already exists.
+    #[error("the build {0:?} already exists, pass --overwrite to replace it")]
+    BuildExists(PathBuf),
+    /// A build cannot be modified as it is deployed.
+    #[error("the build {0:?} is deployed, undeploy it first with `dull undeploy --build {0:?}`")]
+    BuildDeployed(PathBuf),
+    /// A target lies under the files that dull manages, e.g., the builds.
+    #[error("the target {target:?} of the leaf {leaf:?} lies under {managed:?}, which is managed by dull")]
+    ManagedTarget {
+        target: PathBuf,
+        leaf: PathBuf,
+        managed: PathBuf,
+    },
+    /// Following the symlinks under a path leads back to one of its ancestors.
+    #[error("symlink cycle in {path:?}: {link:?} points back to {resolved:?}")]
+    SymlinkCycle {
+        path: PathBuf,
+        link: PathBuf,
+        resolved: PathBuf,
+    },
+    /// A path cannot be created, modified or removed due to its permissions or the permissions of
+    /// its parent directory.
+    #[error("permission denied for {0:?}")]
+    PermissionDenied(PathBuf),
+    /// Another dull operation holds the lock.
+    #[error("another dull operation is in progress (remove {0:?} if it is stale)")]
+    Locked(PathBuf),
+}
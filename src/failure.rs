@@ -0,0 +1,44 @@
+/// Denotes the category of a failure, which determines the exit status of dull. The category is attached
+/// to an error as its context, so that it can be found through `anyhow::Error::downcast_ref`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Failure {
+    /// The configuration could not be read or resolved.
+    Config,
+    /// A conflict or an unmet precondition, e.g., a missing build, prevented the operation.
+    Precondition,
+    /// A transaction failed and its changes were rolled back.
+    RolledBack,
+    /// A transaction failed and the filesystem could not be restored.
+    Fatal,
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Failure::Config => f.write_str("invalid configuration"),
+            Failure::Precondition => f.write_str("precondition failed"),
+            Failure::RolledBack => f.write_str("rolled back the changes"),
+            Failure::Fatal => f.write_str("could not restore the filesystem"),
+        }
+    }
+}
+
+impl Failure {
+    /// Returns the exit status of the failures in this category.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Failure::Config => 2,
+            Failure::Precondition => 3,
+            Failure::RolledBack => 4,
+            Failure::Fatal => 5,
+        }
+    }
+
+    /// Returns the exit status for the given error, which is 1 if its failure is not categorized.
+    /// The outermost category is used if there are multiple.
+    pub fn exit_code_of(err: &anyhow::Error) -> u8 {
+        err.downcast_ref::<Failure>()
+            .map(|failure| failure.exit_code())
+            .unwrap_or(1)
+    }
+}
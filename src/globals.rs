@@ -1,5 +1,132 @@
-pub const BUILD_FILE_NAME: &'static str = ".dull-build";
-pub const STATE_FILE_NAME: &'static str = ".dull-state";
-pub const LINKTHIS_FILENAME: &'static str = ".dull-linkthis";
-pub const LINKTHESE_FILENAME: &'static str = ".dull-linkthese";
-pub const DEFAULT_IGNOREFILES: &'static [&'static str] = &[LINKTHIS_FILENAME, LINKTHESE_FILENAME];
+use std::{path::PathBuf, sync::OnceLock};
+
+pub const DEFAULT_CONFIG_FILE_NAME: &str = "config.toml";
+pub const CONFIG_ENV_VAR: &str = "DULL_CONFIG";
+pub const HOME_ENV_VAR: &str = "DULL_HOME";
+pub const BUILD_FILE_NAME: &str = ".dull-build";
+pub const STATE_FILE_NAME: &str = ".dull-state";
+pub const IGNORELIST_FILE_NAME: &str = ".dull-ignorelist";
+pub const TRANSACTIONS_FILE_NAME: &str = ".dull-transactions";
+pub const UNDO_FILE_NAME: &str = ".dull-undo";
+pub const MODULES_FILE_NAME: &str = ".dull-modules";
+pub const MANIFEST_FILE_NAME: &str = ".dull-manifest";
+pub const LOCK_FILE_NAME: &str = ".dull-lock";
+pub const DEPLOYED_FILE_NAME: &str = ".dull-deployed";
+pub const LINKTHIS_FILENAME: &str = ".dull-linkthis";
+pub const LINKTHESE_FILENAME: &str = ".dull-linkthese";
+pub const LINKTHESE_RECURSIVE_FILENAME: &str = ".dull-linkthese-recursive";
+pub const DULLIGNORE_FILENAME: &str = ".dullignore";
+pub const DEFAULT_IGNOREFILES: &[&str] = &[
+    LINKTHIS_FILENAME,
+    LINKTHESE_FILENAME,
+    LINKTHESE_RECURSIVE_FILENAME,
+    DULLIGNORE_FILENAME,
+];
+
+pub const DEFAULT_MAX_RETRIES: u32 = 3;
+pub const DEFAULT_MAX_PRIMITIVES: usize = 10000;
+
+static DATA_DIR: OnceLock<PathBuf> = OnceLock::new();
+static STATE_DIR: OnceLock<PathBuf> = OnceLock::new();
+static MAX_RETRIES: OnceLock<u32> = OnceLock::new();
+static PRIMITIVE_LIMIT: OnceLock<PrimitiveLimit> = OnceLock::new();
+static PRESERVE_TIMESTAMPS: OnceLock<bool> = OnceLock::new();
+
+/// Limits the number of primitives of a single transaction.
+#[derive(Clone, Copy, Debug)]
+pub struct PrimitiveLimit {
+    pub max: usize,
+    /// If set, transactions exceeding the limit are refused instead of warned about.
+    pub strict: bool,
+}
+
+/// Returns the directory given by `$DULL_HOME`, which holds all the files of dull if it is set.
+fn home_dir() -> Option<PathBuf> {
+    std::env::var_os(HOME_ENV_VAR)
+        .filter(|p| !p.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Returns the dull directory under the XDG base directory given by `xdg_var`, falling back to the
+/// `fallback` directory under the home directory, or the current directory if neither can be determined.
+fn xdg_dir(xdg_var: &str, fallback: &str) -> PathBuf {
+    std::env::var_os(xdg_var)
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(fallback)))
+        .map(|p| p.join("dull"))
+        .unwrap_or(PathBuf::from("."))
+}
+
+/// Returns the default data directory, i.e., `$DULL_HOME`, `$XDG_DATA_HOME/dull` or `~/.local/share/dull`.
+fn default_data_dir() -> PathBuf {
+    home_dir().unwrap_or_else(|| xdg_dir("XDG_DATA_HOME", ".local/share"))
+}
+
+/// Returns the default state directory, i.e., `$DULL_HOME`, `$XDG_STATE_HOME/dull` or `~/.local/state/dull`.
+fn default_state_dir() -> PathBuf {
+    home_dir().unwrap_or_else(|| xdg_dir("XDG_STATE_HOME", ".local/state"))
+}
+
+/// Sets the directory that holds the builds and the transactions. The state is also kept in the given
+/// directory, so that separate data directories do not share their states. Has no effect if it was already set.
+pub fn init_data_dir(data_dir: Option<PathBuf>) {
+    if let Some(data_dir) = &data_dir {
+        STATE_DIR.get_or_init(|| data_dir.clone());
+    }
+    DATA_DIR.get_or_init(|| data_dir.unwrap_or_else(default_data_dir));
+}
+
+/// Returns the directory that holds the state, i.e., the latest build.
+pub fn state_dir() -> &'static PathBuf {
+    STATE_DIR.get_or_init(default_state_dir)
+}
+
+/// Returns the directory that holds the builds and the transactions.
+pub fn data_dir() -> &'static PathBuf {
+    DATA_DIR.get_or_init(default_data_dir)
+}
+
+/// Returns the directory that holds the builds.
+pub fn builds_dir() -> PathBuf {
+    data_dir().join("builds")
+}
+
+/// Returns the directory that holds the transactions and their backups.
+pub fn transactions_dir() -> PathBuf {
+    data_dir().join("transactions")
+}
+
+/// Sets the number of times a primitive is retried after a transient error. Has no effect if it was already set.
+pub fn init_max_retries(max_retries: u32) {
+    MAX_RETRIES.get_or_init(|| max_retries);
+}
+
+/// Returns the number of times a primitive is retried after a transient error.
+pub fn max_retries() -> u32 {
+    *MAX_RETRIES.get_or_init(|| DEFAULT_MAX_RETRIES)
+}
+
+/// Sets the limit on the number of primitives of a single transaction. Has no effect if it was already set.
+pub fn init_primitive_limit(primitive_limit: PrimitiveLimit) {
+    PRIMITIVE_LIMIT.get_or_init(|| primitive_limit);
+}
+
+/// Returns the limit on the number of primitives of a single transaction.
+pub fn primitive_limit() -> PrimitiveLimit {
+    *PRIMITIVE_LIMIT.get_or_init(|| PrimitiveLimit {
+        max: DEFAULT_MAX_PRIMITIVES,
+        strict: false,
+    })
+}
+
+/// Sets whether the copied files keep the access and modification times of their sources. Has no
+/// effect if it was already set.
+pub fn init_preserve_timestamps(preserve_timestamps: bool) {
+    PRESERVE_TIMESTAMPS.get_or_init(|| preserve_timestamps);
+}
+
+/// Returns true iff the copied files keep the access and modification times of their sources.
+pub fn preserve_timestamps() -> bool {
+    *PRESERVE_TIMESTAMPS.get_or_init(|| false)
+}
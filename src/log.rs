@@ -0,0 +1,128 @@
+use std::{
+    fmt::Arguments,
+    io::{IsTerminal, Write},
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+};
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(0);
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Denotes the importance of a message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Level {
+    /// Only displayed with the highest verbosity, e.g., the details of the path resolution.
+    Trace,
+    /// Only displayed in the verbose mode.
+    Debug,
+    Info,
+    /// The outcome of a command, e.g., the path of the build. Written to the standard error in the
+    /// quiet mode, so that it is still displayed.
+    Result,
+    Warn,
+    Error,
+}
+
+impl Level {
+    /// Returns the ANSI color code of the messages with this level.
+    fn color_code(&self) -> Option<&'static str> {
+        match self {
+            Level::Trace | Level::Debug => Some("2"),
+            Level::Info | Level::Result => None,
+            Level::Warn => Some("33"),
+            Level::Error => Some("31"),
+        }
+    }
+}
+
+/// The verbosity from which the trace messages are displayed.
+const TRACE_VERBOSITY: u8 = 3;
+
+/// Sets the verbosity, i.e., the number of `-v` flags, and whether only the warnings, errors and
+/// results are displayed. The debug messages are displayed from verbosity 1 on.
+pub fn init(verbosity: u8, quiet: bool) {
+    VERBOSITY.store(verbosity, Ordering::Relaxed);
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+/// Returns the verbosity set by `init`.
+pub fn verbosity() -> u8 {
+    VERBOSITY.load(Ordering::Relaxed)
+}
+
+/// Displays the message with the given level. Warnings and errors are written to the standard error.
+/// Only the warnings, errors and results are displayed in the quiet mode.
+/// The messages are only colored on terminals, unless `NO_COLOR` is set.
+pub fn log(level: Level, args: Arguments) {
+    let quiet = QUIET.load(Ordering::Relaxed);
+    let min_verbosity = match level {
+        Level::Trace => TRACE_VERBOSITY,
+        Level::Debug => 1,
+        _ => 0,
+    };
+    if verbosity() < min_verbosity {
+        return;
+    }
+    if quiet && matches!(level, Level::Trace | Level::Debug | Level::Info) {
+        return;
+    }
+    let to_stderr = match level {
+        Level::Trace | Level::Debug | Level::Info => false,
+        Level::Result => quiet,
+        Level::Warn | Level::Error => true,
+    };
+    let is_terminal = if to_stderr {
+        std::io::stderr().is_terminal()
+    } else {
+        std::io::stdout().is_terminal()
+    };
+    let color_code = level
+        .color_code()
+        .filter(|_| is_terminal && std::env::var_os("NO_COLOR").is_none());
+    let line = match color_code {
+        Some(color_code) => format!("\x1b[{}m{}\x1b[0m", color_code, args),
+        None => format!("{}", args),
+    };
+    let _ = if to_stderr {
+        writeln!(std::io::stderr(), "{}", line)
+    } else {
+        writeln!(std::io::stdout(), "{}", line)
+    };
+}
+
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        $crate::log::log($crate::log::Level::Trace, format_args!($($arg)*))
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        $crate::log::log($crate::log::Level::Debug, format_args!($($arg)*))
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::log::log($crate::log::Level::Info, format_args!($($arg)*))
+    };
+}
+
+macro_rules! result {
+    ($($arg:tt)*) => {
+        $crate::log::log($crate::log::Level::Result, format_args!($($arg)*))
+    };
+}
+
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        $crate::log::log($crate::log::Level::Warn, format_args!($($arg)*))
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::log::log($crate::log::Level::Error, format_args!($($arg)*))
+    };
+}
+
+pub(crate) use {debug, error, info, result, trace, warning};
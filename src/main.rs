@@ -1,14 +1,28 @@
-use std::path::PathBuf;
+use std::{
+    collections::HashSet,
+    io::Write,
+    path::{Path, PathBuf},
+};
 
 use anyhow::Context;
 use clap::Parser;
+use itertools::Itertools;
 
-use crate::transaction::TxProcessor;
+use crate::{
+    config_parser::{DeployMode, Settings},
+    transaction::{ActualFilesystem, DisplayMode, TxBuilder, TxProcessor},
+};
+use failure::Failure;
 use transaction::Transaction;
-use virtual_system::{VirtualSystem, VirtualSystemBuilder};
+use virtual_system::{LinkState, Undeployable, VirtualSystem, VirtualSystemBuilder};
 
+mod cache;
 mod config_parser;
+mod error;
+mod failure;
 mod globals;
+mod log;
+mod manifest;
 mod module_parser;
 mod transaction;
 mod utils;
@@ -17,9 +31,33 @@ mod virtual_system;
 #[derive(clap::Parser)]
 #[command(author, version, about)]
 struct CliArgs {
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    /// Show more detailed information for debugging; repeat to show the primitives (-vv) and the path resolution (-vvv)
+    verbose: u8,
+
     #[arg(short, long, default_value = "false")]
-    /// Show more detailed information for debugging
-    verbose: bool,
+    /// Only show the warnings, errors and results
+    quiet: bool,
+
+    #[arg(long, default_value = "false")]
+    /// Show a progress line instead of the individual filesystem modifications
+    progress: bool,
+
+    #[arg(long, value_name = "PATH")]
+    /// Directory that holds the builds, the transactions and the state [default: $DULL_HOME or $XDG_DATA_HOME/dull]
+    data_dir: Option<PathBuf>,
+
+    #[arg(long, value_name = "N", default_value_t = globals::DEFAULT_MAX_RETRIES)]
+    /// Number of times a filesystem modification is retried after a transient error
+    retries: u32,
+
+    #[arg(long, value_name = "N", default_value_t = globals::DEFAULT_MAX_PRIMITIVES)]
+    /// Number of filesystem modifications in a single transaction above which a warning is shown
+    max_primitives: usize,
+
+    #[arg(long, default_value = "false")]
+    /// Refuse the transactions above the limit instead of warning
+    strict: bool,
 
     #[command(subcommand)]
     command: CliCommand,
@@ -29,13 +67,52 @@ struct CliArgs {
 enum CliCommand {
     /// Build the modules and generate a virtual filesystem
     Build {
-        #[arg(value_name = "FILE", default_value = "config.toml")]
-        /// The build configuration file
-        config: PathBuf,
+        #[arg(value_name = "FILE")]
+        /// The build configuration file [default: $DULL_CONFIG or config.toml]
+        config: Option<PathBuf>,
+
+        #[arg(short, long, value_name = "NAME", conflicts_with = "config")]
+        /// Use the configuration file config.<NAME>.toml
+        profile: Option<String>,
 
         #[arg(short, long)]
-        /// Name of the generated build
+        /// Name of the generated build [default: the current time]
         name: Option<String>,
+
+        #[arg(long, default_value = "false")]
+        /// Replace the existing build with the same name
+        overwrite: bool,
+
+        #[arg(long, default_value = "false", conflicts_with_all = ["name", "overwrite"])]
+        /// Only validate the configuration and the modules without creating a build
+        check: bool,
+
+        #[arg(long, default_value = "false")]
+        /// Skip the modules that cannot be parsed instead of failing
+        keep_going: bool,
+
+        #[arg(long, value_name = "FILE", conflicts_with = "check")]
+        /// Also pack the generated build into the given .tar.gz archive
+        archive: Option<PathBuf>,
+
+        #[arg(long, value_name = "DIR", conflicts_with_all = ["config", "profile"], requires = "target")]
+        /// Build the given directory as the only module instead of reading a configuration file
+        module_dir: Option<PathBuf>,
+
+        #[arg(long, value_name = "PATH", requires = "module_dir")]
+        /// The target of the module given with --module-dir
+        target: Option<PathBuf>,
+    },
+
+    /// Validate the configuration without parsing the modules or building
+    Validate {
+        #[arg(value_name = "FILE")]
+        /// The configuration file to validate [default: $DULL_CONFIG or config.toml]
+        config: Option<PathBuf>,
+
+        #[arg(short, long, value_name = "NAME", conflicts_with = "config")]
+        /// Use the configuration file config.<NAME>.toml
+        profile: Option<String>,
     },
 
     /// Deploy a build to the system
@@ -44,19 +121,163 @@ enum CliCommand {
         /// Path to the build to deploy
         build: Option<PathBuf>,
 
+        #[arg(long, value_name = "FILE", conflicts_with = "build")]
+        /// Unpack the build from the given archive into the builds and deploy it
+        archive: Option<PathBuf>,
+
         #[arg(long, default_value = "false")]
-        /// Perform a hard deploy
+        /// Perform a hard deploy for the modules that do not specify a mode
         hard: bool,
 
+        #[arg(long, default_value = "false", conflicts_with = "hard")]
+        /// Hard link the files for the modules that do not specify a mode
+        hardlink: bool,
+
+        #[arg(long, default_value = "false", conflicts_with_all = ["hard", "hardlink"])]
+        /// Link the files for the modules that do not specify a mode, overriding the default mode
+        soft: bool,
+
+        #[arg(long, default_value = "false")]
+        /// Keep the access and modification times of the sources in the copied files
+        preserve_timestamps: bool,
+
+        #[arg(long, default_value = "false")]
+        /// Copy the files that would be linked to a different device instead of linking them
+        copy_cross_device: bool,
+
+        #[arg(long, default_value = "false")]
+        /// Link the files through relative paths from their targets, so that they can be moved together
+        relative_links: bool,
+
+        #[arg(long, default_value = "false")]
+        /// Do not back up the removed targets, which disables restoring them on rollback
+        no_backup: bool,
+
+        #[arg(long, default_value = "false", conflicts_with = "no_backup")]
+        /// Back up the removed targets even if the settings disable the backups
+        backup: bool,
+
         #[arg(short, long, default_value = "false")]
         /// Remove the targets before deployment (destructive, not advised)
         force: bool,
+
+        #[arg(long, default_value = "false")]
+        /// Only remove the targets that are symlinks into the modules when forcing
+        safe: bool,
+
+        #[arg(short, long, default_value = "false", requires = "force")]
+        /// Remove the targets without asking for confirmation when forcing
+        yes: bool,
+
+        #[arg(long, value_name = "PATH")]
+        /// Deploy the targets under the given root instead of `/`
+        target_root: Option<PathBuf>,
+
+        #[arg(long, value_name = "PATH")]
+        /// Write the equivalent shell script to the given path instead of deploying
+        emit_script: Option<PathBuf>,
+
+        #[arg(long, default_value = "false", conflicts_with = "emit_script")]
+        /// Display the effects of the deployment without modifying the filesystem
+        dry_run: bool,
+
+        #[arg(long, default_value = "false", conflicts_with = "dry_run")]
+        /// Modify the filesystem even if the settings enable the dry runs
+        no_dry_run: bool,
+
+        #[arg(short, long, default_value = "false", conflicts_with = "force")]
+        /// Ask whether to skip or overwrite each conflicting target
+        interactive: bool,
+
+        #[arg(long, value_name = "NAMES", value_delimiter = ',')]
+        /// Only deploy the modules with the given comma-separated names
+        only: Vec<String>,
+
+        #[arg(long, value_name = "PREFIX")]
+        /// Only deploy the targets under the given prefix, e.g., /etc
+        under: Option<PathBuf>,
+
+        #[arg(long, value_name = "GLOB")]
+        /// Do not deploy the targets matching the given pattern or under the matching directories (repeatable)
+        exclude_target: Vec<String>,
+    },
+    /// Clear the deployed files of the deployed build
+    Undeploy {
+        #[arg(long, value_name = "PATH")]
+        /// Path to the build to undeploy instead of the most recently deployed one
+        build: Option<PathBuf>,
+
+        #[arg(long, value_name = "PREFIX")]
+        /// Only undeploy the targets under the given prefix, e.g., /etc
+        under: Option<PathBuf>,
+    },
+
+    /// Replace the deployed build with another one in a single step
+    Relink {
+        #[arg(value_name = "PATH")]
+        /// Path to the build to deploy instead of the latest build
+        build: Option<PathBuf>,
+
+        #[arg(long, default_value = "false")]
+        /// Perform a hard deploy for the modules that do not specify a mode
+        hard: bool,
+
+        #[arg(long, default_value = "false", conflicts_with = "hard")]
+        /// Hard link the files for the modules that do not specify a mode
+        hardlink: bool,
+
+        #[arg(long, default_value = "false", conflicts_with_all = ["hard", "hardlink"])]
+        /// Link the files for the modules that do not specify a mode, overriding the default mode
+        soft: bool,
+    },
+
+    /// Inspect the build configuration
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
     },
-    /// Clear the deployed files of the latest build
-    Undeploy,
 
     /// Show information about the builds
-    Info,
+    Info {
+        #[arg(long, default_value = "false")]
+        /// Output the information in JSON
+        json: bool,
+    },
+
+    /// Show the state of the deployed files of the latest build
+    Status,
+
+    /// Find the targets of the deployed builds that the latest build no longer has
+    Orphans {
+        #[arg(value_name = "PATH")]
+        /// Path to the build to compare against instead of the latest build
+        build: Option<PathBuf>,
+
+        #[arg(long, default_value = "false")]
+        /// Remove the orphaned targets
+        remove: bool,
+    },
+
+    /// Find the deployed links of the latest build that no longer resolve
+    Doctor {
+        #[arg(long, default_value = "false")]
+        /// Rebuild and redeploy to repair the dangling links
+        fix: bool,
+
+        #[arg(long, value_name = "FILE", requires = "fix")]
+        /// The build configuration file to rebuild with [default: $DULL_CONFIG or config.toml]
+        config: Option<PathBuf>,
+
+        #[arg(
+            short,
+            long,
+            value_name = "NAME",
+            requires = "fix",
+            conflicts_with = "config"
+        )]
+        /// Rebuild with the configuration file config.<NAME>.toml
+        profile: Option<String>,
+    },
 
     /// Clear the transaction cache.
     ClearCache,
@@ -64,94 +285,686 @@ enum CliCommand {
     /// Clear the builds.
     ClearBuilds,
 
+    /// Check that the backups of the cached transactions are intact.
+    Verify,
+
+    /// Remove a build by undoing its build transaction
+    RemoveBuild {
+        #[arg(value_name = "PATH")]
+        /// Path to the build to remove
+        build: PathBuf,
+    },
+
+    /// Remove all but the newest builds and transactions.
+    Prune {
+        #[arg(short, long, default_value = "5")]
+        /// Number of builds and transactions to keep
+        keep: usize,
+    },
+
+    /// List the recorded transactions, newest first.
+    History {
+        #[arg(long, value_name = "DURATION", value_parser = utils::parse_duration)]
+        /// Only list the transactions from within the given duration, e.g., `7d`
+        since: Option<std::time::Duration>,
+    },
+
     /// Runs an atomic transaction (advanced).
     RunTransaction {
         #[arg(short, long, value_name = "PATH")]
         /// Path to the transaction file
         file: PathBuf,
+
+        #[arg(long, default_value = "false")]
+        /// Run the recorded undo of the transaction instead
+        rollback: bool,
     },
 }
 
-fn main() -> anyhow::Result<()> {
+#[derive(clap::Subcommand)]
+enum ConfigCommand {
+    /// Print the resolved configuration, with its inclusions merged and the modules of the other hosts left out
+    Show {
+        #[arg(value_name = "FILE")]
+        /// The build configuration file [default: $DULL_CONFIG or config.toml]
+        config: Option<PathBuf>,
+
+        #[arg(short, long, value_name = "NAME", conflicts_with = "config")]
+        /// Use the configuration file config.<NAME>.toml
+        profile: Option<String>,
+
+        #[arg(long, default_value = "false")]
+        /// Output the configuration in JSON instead of TOML
+        json: bool,
+    },
+}
+
+#[derive(serde::Serialize)]
+struct ModuleInfoOutput {
+    name: String,
+    origin: Option<PathBuf>,
+}
+
+#[derive(serde::Serialize)]
+struct BuildInfoOutput {
+    path: PathBuf,
+    name: String,
+    /// Seconds since the Unix epoch, unknown for older builds.
+    created: Option<u64>,
+    version: Option<String>,
+    leaves: usize,
+    modules: Vec<ModuleInfoOutput>,
+    deployed: bool,
+}
+
+#[derive(serde::Serialize)]
+struct InfoOutput {
+    latest_build: Option<PathBuf>,
+    deployed_build: Option<PathBuf>,
+    builds: Vec<BuildInfoOutput>,
+}
+
+/// Denotes how a conflicting target is handled during an interactive deployment.
+enum ConflictResolution {
+    Skip,
+    Overwrite,
+    Abort,
+}
+
+/// Asks on the standard input how the given conflicting target should be handled.
+fn prompt_conflict(target: &Path, state: &LinkState) -> anyhow::Result<ConflictResolution> {
+    let reason = match state {
+        LinkState::WrongTarget(linked) => format!("links to {:?}", linked),
+        _ => String::from("is occupied"),
+    };
+    loop {
+        print!("{:?} {}. [s]kip, [o]verwrite, or [a]bort? ", target, reason);
+        std::io::stdout().flush()?;
+        let mut answer = String::new();
+        if std::io::stdin().read_line(&mut answer)? == 0 {
+            return Ok(ConflictResolution::Abort);
+        }
+        match answer.trim() {
+            "s" | "skip" => return Ok(ConflictResolution::Skip),
+            "o" | "overwrite" => return Ok(ConflictResolution::Overwrite),
+            "a" | "abort" => return Ok(ConflictResolution::Abort),
+            _ => continue,
+        }
+    }
+}
+
+/// Lists the targets that will be removed and asks on the standard input whether to proceed. Only
+/// typing `yes` confirms the removal.
+fn confirm_removal(targets: &[PathBuf]) -> anyhow::Result<bool> {
+    println!("The following {} targets will be removed:", targets.len());
+    for target in targets {
+        println!(" - {:?}", target);
+    }
+    print!("Type `yes` to proceed: ");
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim() == "yes")
+}
+
+/// Prompts for each conflicting target of the virtual system, and removes the ones that should be
+/// overwritten. Returns the virtual system with the rest of the conflicting targets skipped.
+fn resolve_conflicts(
+    virt_system: VirtualSystem<Undeployable>,
+    tx_proc: &mut TxProcessor,
+) -> anyhow::Result<VirtualSystem<Undeployable>> {
+    let mut txb = TxBuilder::empty();
+    let fs = ActualFilesystem;
+    let mut skipped_targets = HashSet::new();
+    for (target, state) in virt_system.diff()? {
+        if !matches!(state, LinkState::WrongTarget(_) | LinkState::TargetOccupied) {
+            continue;
+        }
+        match prompt_conflict(&target, &state)? {
+            ConflictResolution::Skip => {
+                skipped_targets.insert(target);
+            }
+            ConflictResolution::Overwrite => txb.remove_any(&target, &fs)?,
+            ConflictResolution::Abort => anyhow::bail!("deployment aborted"),
+        }
+    }
+    if txb.len() > 0 {
+        txb.build("ResolveConflicts")
+            .and_then(|tx| tx_proc.run_required(tx))?;
+    }
+    Ok(virt_system.with_skipped_targets(skipped_targets))
+}
+
+/// Returns the mode of the modules that do not specify one. The flags take precedence over the
+/// default mode of the settings.
+fn default_mode(hard: bool, hardlink: bool, soft: bool, settings: &Settings) -> DeployMode {
+    if hard {
+        DeployMode::Hard
+    } else if hardlink {
+        DeployMode::HardLink
+    } else if soft {
+        DeployMode::Soft
+    } else {
+        settings.default_mode
+    }
+}
+
+/// Returns the path of the configuration file. An explicitly given path takes precedence over the
+/// profile, which takes precedence over the environment variable.
+fn resolve_config_path(config: Option<PathBuf>, profile: Option<String>) -> PathBuf {
+    config
+        .or_else(|| profile.map(|profile| PathBuf::from(format!("config.{}.toml", profile))))
+        .or_else(|| std::env::var_os(globals::CONFIG_ENV_VAR).map(PathBuf::from))
+        .unwrap_or(PathBuf::from(globals::DEFAULT_CONFIG_FILE_NAME))
+}
+
+/// Reads and resolves the configuration file chosen by the arguments, see `resolve_config_path`.
+fn read_config(
+    config: Option<PathBuf>,
+    profile: Option<String>,
+) -> anyhow::Result<config_parser::ResolvedConfig> {
+    config_parser::read_config(resolve_config_path(config, profile)).context(Failure::Config)
+}
+
+/// Reports the error and exits with the status of its failure category.
+fn main() -> std::process::ExitCode {
+    match run() {
+        Ok(()) => std::process::ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {:?}", err);
+            let exit_code = Failure::exit_code_of(&err);
+            if exit_code == Failure::Fatal.exit_code() {
+                eprintln!(
+                    "The filesystem could not be restored. The backups are kept in the transaction directories in {:?}.",
+                    globals::transactions_dir()
+                );
+            }
+            std::process::ExitCode::from(exit_code)
+        }
+    }
+}
+
+fn run() -> anyhow::Result<()> {
     let cli = CliArgs::parse();
+    log::init(cli.verbose, cli.quiet);
+    globals::init_data_dir(cli.data_dir);
+    globals::init_max_retries(cli.retries);
+    globals::init_primitive_limit(globals::PrimitiveLimit {
+        max: cli.max_primitives,
+        strict: cli.strict,
+    });
+    let display_mode = if cli.quiet {
+        DisplayMode::Silent
+    } else {
+        DisplayMode::from_flags(cli.verbose, cli.progress)
+    };
     match cli.command {
-        CliCommand::Build { name, config } => {
-            println!("Building...");
-            let config = config_parser::read_config(config)?;
-            let build_path = VirtualSystemBuilder::from_config(&config)
-                .build(name, cli.verbose)
-                .context("build failed")?;
-            utils::set_state(&build_path.clone().into_os_string().to_string_lossy())?;
-            println!("Build complete at path {:?}", build_path)
+        CliCommand::Build {
+            name,
+            config,
+            profile,
+            overwrite,
+            check,
+            keep_going,
+            archive,
+            module_dir,
+            target,
+        } => {
+            let read_build_config = || match (module_dir, target) {
+                (Some(module_dir), Some(target)) => Ok(
+                    config_parser::ResolvedConfig::single_module(module_dir, target),
+                ),
+                _ => read_config(config, profile),
+            };
+            if check {
+                log::info!("Checking...");
+                let config = read_build_config()?;
+                // The invalid module sources are reported while parsing when skipping them.
+                if !keep_going {
+                    config.validate().context(Failure::Config)?;
+                }
+                let num_links = VirtualSystemBuilder::from_config(&config)
+                    .keep_going(keep_going)
+                    .check()
+                    .context("check failed")?;
+                log::result!(
+                    " ✓ {} modules resolve to {} links",
+                    config.modules.len(),
+                    num_links
+                );
+            } else {
+                let _lock = utils::acquire_lock()?;
+                log::info!("Building...");
+                let config = read_build_config()?;
+                if !keep_going {
+                    config.validate().context(Failure::Config)?;
+                }
+                let build_path = VirtualSystemBuilder::from_config(&config)
+                    .keep_going(keep_going)
+                    .build(name, overwrite, display_mode)
+                    .context("build failed")?;
+                utils::set_state(&build_path)?;
+                log::result!("Build complete at path {:?}", build_path);
+                if let Some(archive_path) = archive {
+                    cache::pack_build(&build_path, &archive_path)?;
+                    log::result!("Build packed into {:?}", archive_path);
+                }
+            }
+        }
+        CliCommand::Validate { config, profile } => {
+            let config = read_config(config, profile)?;
+            config.validate().context(Failure::Config)?;
+            log::result!(" ✓ {} modules are valid", config.modules.len());
         }
         CliCommand::Deploy {
             build: build_path,
+            archive,
             hard,
+            hardlink,
+            soft,
+            preserve_timestamps,
+            copy_cross_device,
+            relative_links,
+            no_backup,
+            backup,
             force,
+            safe,
+            yes,
+            target_root,
+            emit_script,
+            dry_run,
+            no_dry_run,
+            interactive,
+            only,
+            under,
+            exclude_target,
         } => {
-            println!("Deploying...");
+            let _lock = utils::acquire_lock()?;
+            globals::init_preserve_timestamps(preserve_timestamps);
+            log::info!("Deploying...");
             let effective_build_path = if let Some(given_path) = build_path {
                 given_path
+            } else if let Some(archive_path) = archive {
+                let build_path = cache::unpack_build(&archive_path)?;
+                log::info!("Unpacked the build into {:?}", build_path);
+                utils::set_state(&build_path)?;
+                build_path
             } else {
                 utils::get_state()
-                    .context(format!(
-                        "no state was found, explicitly supply the target using --build"
-                    ))?
+                    .context("no state was found, explicitly supply the target using --build")?
                     .into()
             };
-            let mut tx_proc = TxProcessor::new("deployment", cli.verbose);
+            let mut virt_system = VirtualSystem::read(effective_build_path)?;
+            let settings = virt_system.settings();
+            let mut tx_proc = TxProcessor::new("deployment", display_mode);
+            if emit_script.is_some() {
+                tx_proc = tx_proc.emitting_script();
+            } else if dry_run || (settings.dry_run && !no_dry_run) {
+                tx_proc = tx_proc.dry_running();
+            }
+            if no_backup || (!settings.backup && !backup) {
+                log::warning!(
+                    "Backups are disabled, the removed targets cannot be restored on rollback"
+                );
+                tx_proc = tx_proc.without_backups();
+            }
+            virt_system = virt_system
+                .with_only_modules(only)?
+                .with_excluded_targets(&exclude_target)?
+                .with_copy_cross_device(copy_cross_device)
+                .with_relative_links(relative_links || settings.relative_links);
+            if let Some(under) = under {
+                virt_system = virt_system.with_under(&under)?;
+            }
+            if let Some(target_root) = target_root {
+                virt_system = virt_system.with_target_root(target_root);
+            }
+            if interactive {
+                virt_system = resolve_conflicts(virt_system, &mut tx_proc)?;
+            }
             let virt_system = if force {
-                VirtualSystem::read(effective_build_path)?.clear_targets(&mut tx_proc)?
+                let targets = virt_system.targets_to_clear(safe)?;
+                let needs_confirmation =
+                    settings.confirm && !yes && !tx_proc.is_simulating() && !targets.is_empty();
+                if needs_confirmation && !confirm_removal(&targets)? {
+                    anyhow::bail!("deployment aborted");
+                }
+                virt_system.clear_targets(&targets, &mut tx_proc)?
             } else {
-                VirtualSystem::read(effective_build_path)?
+                virt_system
             }
             .prepare_deployment(&mut tx_proc)
             .context("preparation failed")?;
-            let res = if hard {
-                virt_system.hard_deploy(globals::DEFAULT_IGNOREFILES, &mut tx_proc)
+            let default_mode = default_mode(hard, hardlink, soft, &settings);
+            let ignore_filenames = virt_system.ignore_filenames();
+            virt_system
+                .deploy(default_mode, &ignore_filenames, &mut tx_proc)
+                .context("deployment failed")?;
+            if let (Some(script_path), Some(script)) = (emit_script, tx_proc.script()) {
+                std::fs::write(&script_path, script)
+                    .context(format!("could not write the script to {:?}", script_path))?;
+                log::result!("Script written to {:?}", script_path);
+            } else if tx_proc.is_simulating() {
+                log::info!("Would deploy: {}", tx_proc.summary());
             } else {
-                virt_system.soft_deploy(&mut tx_proc)
-            };
-            res.context("deployment failed")?;
+                log::info!("Deployed: {}", tx_proc.summary());
+            }
         }
-        CliCommand::Undeploy => {
-            println!("Undeploying...");
-            let mut tx_proc = TxProcessor::new("undeployment", cli.verbose);
-            let last_build_path = utils::get_state()
-                .context("no build was deployed, cannot undeploy")?
-                .into();
-            let virt_system = VirtualSystem::read(last_build_path)?;
+        CliCommand::Undeploy {
+            build: build_path,
+            under,
+        } => {
+            let _lock = utils::acquire_lock()?;
+            log::info!("Undeploying...");
+            let mut tx_proc = TxProcessor::new("undeployment", display_mode);
+            let effective_build_path: PathBuf = if let Some(given_path) = build_path {
+                given_path
+            } else {
+                cache::deployed_build()?
+                    .context("no build is deployed, cannot undeploy")
+                    .context(Failure::Precondition)?
+            };
+            let mut virt_system = VirtualSystem::read(effective_build_path.clone())
+                .context(format!("{:?} is not a build", effective_build_path))?;
+            if !virt_system.is_deployed() {
+                return Err(anyhow::anyhow!(
+                    "the build {:?} is not deployed, cannot undeploy",
+                    effective_build_path
+                ))
+                .context(Failure::Precondition);
+            }
+            if let Some(under) = under {
+                virt_system = virt_system.with_under(&under)?;
+            }
             virt_system
                 .undeploy(&mut tx_proc)
                 .context("undeployment failed")?;
+            log::info!("Undeployed: {}", tx_proc.summary());
+        }
+        CliCommand::Relink {
+            build: build_path,
+            hard,
+            hardlink,
+            soft,
+        } => {
+            let _lock = utils::acquire_lock()?;
+            let old_build_path = cache::deployed_build()?
+                .context("no build is deployed, deploy one first with `dull deploy`")
+                .context(Failure::Precondition)?;
+            let effective_build_path: PathBuf = if let Some(given_path) = build_path {
+                given_path
+            } else {
+                utils::get_state()
+                    .context("no build was found, cannot relink")?
+                    .into()
+            };
+            let old_virt_system = VirtualSystem::read(old_build_path.clone())
+                .context(format!("{:?} is not a build", old_build_path))?;
+            let virt_system = VirtualSystem::read(effective_build_path.clone())
+                .context(format!("{:?} is not a build", effective_build_path))?;
+            let settings = virt_system.settings();
+            let virt_system = virt_system.with_relative_links(settings.relative_links);
+            let default_mode = default_mode(hard, hardlink, soft, &settings);
+            log::info!("Relinking...");
+            // Undeploy the old build and deploy the new one together, so that a failure restores
+            // the old deployment.
+            let mut tx_proc = TxProcessor::new("relink", display_mode);
+            virt_system
+                .relink(old_virt_system, default_mode, &mut tx_proc)
+                .context("relink failed")?;
+            log::result!(" ✓ Relinked {:?}", effective_build_path);
+        }
+        CliCommand::Config {
+            command:
+                ConfigCommand::Show {
+                    config,
+                    profile,
+                    json,
+                },
+        } => {
+            let config = read_config(config, profile)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&config.to_json()?)?);
+            } else {
+                print!(
+                    "{}",
+                    config
+                        .to_toml()
+                        .context("could not serialize the configuration")?
+                );
+            }
         }
-        CliCommand::Info => {
+        CliCommand::Info { json } => {
             let latest_build = utils::get_state()
                 .and_then(|s| VirtualSystem::read(s.into()))
-                .map(|vs| vs.path.to_string_lossy().to_string())
-                .unwrap_or(String::from("N/A"));
-            println!("Latest build: {:?}", latest_build);
-            let virt_systems = glob::glob("./**/.dull-build")
+                .map(|vs| vs.path)
+                .ok();
+            let builds_pattern = globals::builds_dir()
+                .join("**")
+                .join(globals::BUILD_FILE_NAME);
+            let virt_systems = glob::glob(&builds_pattern.to_string_lossy())
                 .context("could not query the filesystem for builds")?
                 .flatten()
                 .flat_map(|path| path.parent().map(|p| p.to_path_buf()))
-                .flat_map(|path| VirtualSystem::read(path));
-            for virt_system in virt_systems {
-                println!("=> build {:?}", virt_system.path);
+                .flat_map(VirtualSystem::read)
+                .collect_vec();
+            let deployed_build = virt_systems
+                .iter()
+                .find(|virt_system| virt_system.is_deployed())
+                .map(|virt_system| virt_system.path.clone());
+            if json {
+                let info = InfoOutput {
+                    latest_build,
+                    deployed_build,
+                    builds: virt_systems
+                        .into_iter()
+                        .map(|virt_system| BuildInfoOutput {
+                            leaves: virt_system.num_leaves(),
+                            modules: virt_system
+                                .modules()
+                                .into_iter()
+                                .map(|(name, origin)| ModuleInfoOutput { name, origin })
+                                .collect(),
+                            deployed: virt_system.is_deployed(),
+                            name: virt_system.info.name,
+                            created: virt_system.info.created,
+                            version: virt_system.info.version,
+                            path: virt_system.path,
+                        })
+                        .collect(),
+                };
+                println!("{}", serde_json::to_string_pretty(&info)?);
+            } else {
+                let latest_build = latest_build
+                    .map(|p| p.to_string_lossy().to_string())
+                    .unwrap_or(String::from("N/A"));
+                let deployed_build = deployed_build
+                    .map(|p| p.to_string_lossy().to_string())
+                    .unwrap_or(String::from("N/A"));
+                println!("Latest build: {:?}", latest_build);
+                println!("Deployed build: {:?}", deployed_build);
+                for virt_system in virt_systems {
+                    if virt_system.is_deployed() {
+                        println!("=> build {:?} (deployed)", virt_system.path);
+                    } else {
+                        println!("=> build {:?}", virt_system.path);
+                    }
+                    let info = &virt_system.info;
+                    if let (Some(created), Some(version)) = (info.created, &info.version) {
+                        println!(
+                            "   created at {} by dull {}, {} modules, {} leaves",
+                            utils::format_timestamp(created),
+                            version,
+                            info.num_modules.unwrap_or_default(),
+                            info.num_leaves.unwrap_or_default()
+                        );
+                    }
+                    for (name, origin) in virt_system.modules() {
+                        match origin {
+                            Some(origin) => println!("   - {:?} defined in {:?}", name, origin),
+                            None => println!("   - {:?}", name),
+                        }
+                    }
+                }
+            }
+        }
+        CliCommand::Status => {
+            let last_build_path = utils::get_state()
+                .context("no state was found, cannot show the status")?
+                .into();
+            let virt_system = VirtualSystem::read(last_build_path)?;
+            for (target, state) in virt_system.diff()? {
+                let problem = match state {
+                    LinkState::Linked => {
+                        println!(" ✓ {:?}", target);
+                        continue;
+                    }
+                    LinkState::WrongTarget(linked) => format!("links to {:?}", linked),
+                    LinkState::Missing => "is missing".to_string(),
+                    LinkState::TargetOccupied => "is occupied".to_string(),
+                };
+                match virt_system.describe_origin(&target) {
+                    Some(origin) => println!(" ✗ {:?} {} ({})", target, problem, origin),
+                    None => println!(" ✗ {:?} {}", target, problem),
+                }
+            }
+        }
+        CliCommand::Orphans {
+            build: build_path,
+            remove,
+        } => {
+            let _lock = utils::acquire_lock()?;
+            let effective_build_path: PathBuf = if let Some(given_path) = build_path {
+                given_path
+            } else {
+                utils::get_state()
+                    .context("no build was found, cannot find the orphans")?
+                    .into()
+            };
+            let virt_system = VirtualSystem::read(effective_build_path.clone())
+                .context(format!("{:?} is not a build", effective_build_path))?;
+            let effective_build_path = effective_build_path.canonicalize().ok();
+            let mut tx_proc = TxProcessor::new("orphans", display_mode);
+            let mut num_orphans = 0;
+            let mut num_removed = 0;
+            for old_build_path in cache::deployed_builds()? {
+                if old_build_path.canonicalize().ok() == effective_build_path {
+                    continue;
+                }
+                let old_virt_system = VirtualSystem::read(old_build_path.clone())
+                    .context(format!("{:?} is not a build", old_build_path))?;
+                let orphans = old_virt_system.orphans(&virt_system)?;
+                for orphan in orphans.iter() {
+                    println!(" ✗ {:?} is left behind by {:?}", orphan, old_build_path);
+                }
+                num_orphans += orphans.len();
+                if remove {
+                    num_removed += old_virt_system
+                        .remove_orphans(&virt_system, &mut tx_proc)
+                        .context("could not remove the orphans")?;
+                    // The build no longer records the removed orphans, so a later failure must not
+                    // restore them.
+                    tx_proc.savepoint();
+                }
+            }
+            if num_orphans == 0 {
+                log::info!(" ✓ No orphaned targets");
+            } else if remove {
+                log::result!(" ✓ Removed {} orphaned targets", num_removed);
+            } else {
+                log::info!("Run `dull orphans --remove` to remove them");
+            }
+        }
+        CliCommand::Doctor {
+            fix,
+            config,
+            profile,
+        } => {
+            let deployed_build_path = cache::deployed_build()?
+                .context("no build is deployed, cannot check the deployment")
+                .context(Failure::Precondition)?;
+            let virt_system = VirtualSystem::read(deployed_build_path)?;
+            let dangling_links = virt_system.dangling_links()?;
+            for (target, linked) in dangling_links.iter() {
+                log::result!(" ✗ {:?} links to the missing {:?}", target, linked);
+            }
+            if dangling_links.is_empty() {
+                log::info!(" ✓ No dangling links");
+            } else if !fix {
+                log::info!("Run `dull doctor --fix` to rebuild and redeploy");
+            } else {
+                let _lock = utils::acquire_lock()?;
+                log::info!("Rebuilding...");
+                let config = read_config(config, profile)?;
+                config.validate().context(Failure::Config)?;
+                let build_path = VirtualSystemBuilder::from_config(&config)
+                    .build(None, false, display_mode)
+                    .context("build failed")?;
+                utils::set_state(&build_path)?;
+                log::info!("Redeploying...");
+                // Undeploy the old build and deploy the new one together, so that a failure
+                // restores the old deployment.
+                let mut tx_proc = TxProcessor::new("repair", display_mode);
+                // Redeploy in the default mode of the repaired deployment, the modes of the modules
+                // are taken from the new build.
+                let deployed_mode = virt_system.deployed_mode();
+                virt_system
+                    .undeploy(&mut tx_proc)
+                    .context("undeployment failed")?;
+                let virt_system = VirtualSystem::read(build_path)?;
+                let settings = virt_system.settings();
+                let default_mode = deployed_mode.unwrap_or(settings.default_mode);
+                let virt_system = virt_system
+                    .with_relative_links(settings.relative_links)
+                    .prepare_deployment(&mut tx_proc)
+                    .context("preparation failed")?;
+                let ignore_filenames = virt_system.ignore_filenames();
+                virt_system
+                    .deploy(default_mode, &ignore_filenames, &mut tx_proc)
+                    .context("deployment failed")?;
+                log::result!(" ✓ Repaired {} dangling links", dangling_links.len());
             }
         }
         CliCommand::ClearCache => {
-            std::fs::remove_dir_all("transactions")?;
+            cache::clear_transactions()?;
         }
         CliCommand::ClearBuilds => {
-            std::fs::remove_dir_all("builds")?;
-        }
-        CliCommand::RunTransaction { file } => {
-            println!("Running the transaction at {:?}...", file);
-            Transaction::read(file)
-                .context("could not read the transaction")?
-                .run_atomic(cli.verbose)
-                .display_report();
+            cache::clear_builds()?;
+        }
+        CliCommand::Verify => {
+            cache::verify()?;
+        }
+        CliCommand::RemoveBuild { build } => {
+            let _lock = utils::acquire_lock()?;
+            log::info!("Removing the build {:?}...", build);
+            let mut tx_proc = TxProcessor::new("build removal", display_mode);
+            VirtualSystem::read(build)?
+                .remove(&mut tx_proc)
+                .context("build removal failed")?;
+        }
+        CliCommand::Prune { keep } => {
+            cache::prune(keep)?;
+        }
+        CliCommand::History { since } => {
+            cache::history(since)?;
+        }
+        CliCommand::RunTransaction { file, rollback } => {
+            let tx = Transaction::read(file.clone()).context("could not read the transaction")?;
+            let tx = if rollback {
+                log::info!("Rolling back the transaction at {:?}...", file);
+                tx.read_undo()
+                    .context("could not read the undo transaction")?
+            } else {
+                log::info!("Running the transaction at {:?}...", file);
+                tx
+            };
+            if let Some(description) = tx.description() {
+                log::info!("Description: {}", description);
+            }
+            tx.validate().context("invalid transaction")?;
+            let tx_result = tx.run_atomic(display_mode);
+            tx_result.display_report();
+            tx_result.into_tx_result()?;
         }
     }
     Ok(())
@@ -0,0 +1,229 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+use crate::{
+    config_parser::{DeployMode, Settings},
+    globals, utils,
+};
+
+/// The content hash of a regular file, along with the metadata it was computed for.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct FileHash {
+    pub len: u64,
+    /// Nanoseconds since the Unix epoch.
+    pub modified: u64,
+    pub hash: u64,
+}
+
+impl FileHash {
+    /// Computes the hash of the regular file at the given path.
+    pub fn compute(path: &Path) -> anyhow::Result<Self> {
+        let metadata = std::fs::metadata(path)
+            .context(format!("could not read the metadata of {:?}", path))?;
+        Ok(Self {
+            len: metadata.len(),
+            modified: modified_nanos(&metadata),
+            hash: utils::content_hash(path)?,
+        })
+    }
+
+    /// Returns the hash of the file at the given path, reusing this hash if the file has not changed since.
+    pub fn refresh(&self, path: &Path) -> anyhow::Result<u64> {
+        let unchanged = std::fs::metadata(path).is_ok_and(|metadata| {
+            metadata.len() == self.len && modified_nanos(&metadata) == self.modified
+        });
+        if unchanged {
+            Ok(self.hash)
+        } else {
+            utils::content_hash(path)
+        }
+    }
+}
+
+fn modified_nanos(metadata: &std::fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+/// Describes a single leaf of a build.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ManifestEntry {
+    /// The path of the link relative to the build directory.
+    pub virtual_target: PathBuf,
+    pub abs_source: PathBuf,
+    pub abs_target: PathBuf,
+    /// The name of the module that generated this leaf.
+    #[serde(default)]
+    pub module: Option<String>,
+    /// The configuration file that defines the module.
+    #[serde(default)]
+    pub origin: Option<PathBuf>,
+    /// The deployment mode of the module that generated this leaf, if it was specified.
+    pub mode: Option<DeployMode>,
+    /// The hash of the source if it is a regular file.
+    #[serde(default)]
+    pub source_hash: Option<FileHash>,
+    /// The permission bits that are applied to the target after the deployment, if specified.
+    #[serde(default)]
+    pub permissions: Option<u32>,
+}
+
+/// Describes the leaves of a build, stored in the build directory.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct Manifest {
+    pub entries: Vec<ManifestEntry>,
+    /// The directories that were created to deploy the leaves, which are removed once they are empty
+    /// after an undeployment.
+    #[serde(default)]
+    pub created_dirs: Vec<PathBuf>,
+    /// Maps the target directories to the permission bits of the directories created under them.
+    #[serde(default)]
+    pub dir_modes: HashMap<PathBuf, u32>,
+    /// The defaults of the deployment flags given in the configuration.
+    #[serde(default)]
+    pub settings: Settings,
+}
+
+impl Manifest {
+    /// Reads the manifest of the build at the given path. Returns `None` for older builds without a manifest.
+    pub fn read(build_path: &Path) -> anyhow::Result<Option<Self>> {
+        let manifest_path = build_path.join(globals::MANIFEST_FILE_NAME);
+        if !manifest_path.exists() {
+            return Ok(None);
+        }
+        let manifest_file = std::fs::File::open(&manifest_path).context(format!(
+            "could not read the manifest at {:?}",
+            manifest_path
+        ))?;
+        serde_json::from_reader(std::io::BufReader::new(manifest_file))
+            .map(Some)
+            .context(format!(
+                "could not deserialize the manifest at {:?}",
+                manifest_path
+            ))
+    }
+
+    /// Writes the manifest into the build at the given path.
+    pub fn write(&self, build_path: &Path) -> anyhow::Result<()> {
+        let manifest_path = build_path.join(globals::MANIFEST_FILE_NAME);
+        let manifest_file = std::fs::File::create(&manifest_path).context(format!(
+            "could not write the manifest at {:?}",
+            manifest_path
+        ))?;
+        serde_json::to_writer(std::io::BufWriter::new(manifest_file), self).context(format!(
+            "could not serialize the manifest into {:?}",
+            manifest_path
+        ))
+    }
+
+    /// Returns the names of the modules that generated the leaves.
+    pub fn module_names(&self) -> HashSet<&str> {
+        self.entries
+            .iter()
+            .flat_map(|entry| entry.module.as_deref())
+            .collect()
+    }
+
+    /// Returns the source hashes of the leaves that have one, keyed by their virtual targets.
+    pub fn source_hashes(&self) -> HashMap<PathBuf, FileHash> {
+        self.entries
+            .iter()
+            .flat_map(|entry| Some((entry.virtual_target.clone(), entry.source_hash.clone()?)))
+            .collect()
+    }
+
+    /// Returns the permission bits of the leaves that specify them, keyed by their virtual targets.
+    pub fn permissions(&self) -> HashMap<PathBuf, u32> {
+        self.entries
+            .iter()
+            .flat_map(|entry| Some((entry.virtual_target.clone(), entry.permissions?)))
+            .collect()
+    }
+
+    /// Returns the deployment modes of the leaves that specify one, keyed by their virtual targets.
+    pub fn modes(&self) -> HashMap<PathBuf, DeployMode> {
+        self.entries
+            .iter()
+            .flat_map(|entry| Some((entry.virtual_target.clone(), entry.mode?)))
+            .collect()
+    }
+}
+
+/// Describes a build, stored in its build file. The older builds only store their names.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct BuildInfo {
+    pub name: String,
+    /// Seconds since the Unix epoch.
+    pub created: Option<u64>,
+    /// The version of dull that created the build.
+    pub version: Option<String>,
+    pub num_modules: Option<usize>,
+    pub num_leaves: Option<usize>,
+}
+
+impl BuildInfo {
+    /// Describes a build that is created now by this version of dull.
+    pub fn new(name: String, num_modules: usize, num_leaves: usize) -> Self {
+        Self {
+            name,
+            created: Some(utils::now_secs()),
+            version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            num_modules: Some(num_modules),
+            num_leaves: Some(num_leaves),
+        }
+    }
+
+    /// Reads the build file of the build at the given path, falling back to the legacy plain-text format.
+    pub fn read(build_path: &Path) -> anyhow::Result<Self> {
+        let build_file_path = build_path.join(globals::BUILD_FILE_NAME);
+        let contents = std::fs::read_to_string(&build_file_path).context(format!(
+            "could not read the build file {:?}",
+            build_file_path
+        ))?;
+        Ok(serde_json::from_str(&contents).unwrap_or_else(|_| Self {
+            name: contents.trim().to_string(),
+            created: None,
+            version: None,
+            num_modules: None,
+            num_leaves: None,
+        }))
+    }
+
+    /// Writes the build file into the build at the given path.
+    pub fn write(&self, build_path: &Path) -> anyhow::Result<()> {
+        let build_file_path = build_path.join(globals::BUILD_FILE_NAME);
+        let contents = serde_json::to_string(self).context(format!(
+            "could not serialize the build information into {:?}",
+            build_file_path
+        ))?;
+        std::fs::write(&build_file_path, contents).context(format!(
+            "could not generate the build information at {:?}",
+            build_file_path
+        ))
+    }
+
+    /// Returns true iff the build can be used by this version of dull, i.e., it was created by a
+    /// version with the same major version, or the same minor version before 1.0.
+    pub fn is_compatible(&self) -> bool {
+        let Some(version) = &self.version else {
+            return true;
+        };
+        let compatible_part = |v: &str| {
+            let parts = v.split('.').collect::<Vec<_>>();
+            match parts.as_slice() {
+                ["0", minor, ..] => format!("0.{}", minor),
+                [major, ..] => major.to_string(),
+                [] => String::new(),
+            }
+        };
+        compatible_part(version) == compatible_part(env!("CARGO_PKG_VERSION"))
+    }
+}
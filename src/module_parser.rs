@@ -1,70 +1,215 @@
-use std::path::PathBuf;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
 use anyhow::Context;
 use itertools::Itertools;
 use walkdir::WalkDir;
 
-use crate::{config_parser::ModuleConfig, globals, utils};
+use crate::{
+    config_parser::{GlobalConfig, ModuleConfig, PrefixStrip},
+    error::DullError,
+    globals, log, utils,
+};
 
 #[derive(Default, Debug, Clone)]
 pub struct Module {
     module_path: PathBuf,
     /// Denotes the list of files/folders that are exposed by this module and should be linked.
     sources: Vec<PathBuf>,
+    /// Maps the paths relative to the target to the paths they are deployed at.
+    renames: HashMap<PathBuf, PathBuf>,
 }
 
 impl Module {
     /// Consumes `self` and generates a set of links that represent the links
     /// that should be generated, with the targets are all prefixed with `target_prefix`.
-    pub fn emplace(self, target_prefix: &PathBuf) -> Vec<utils::ResolvedLink> {
-        self.sources
+    /// Fails if a resolved target escapes the prefix, e.g., through `..` components.
+    pub fn emplace(mut self, target_prefix: &Path) -> anyhow::Result<Vec<utils::ResolvedLink>> {
+        let abs_target_prefix = utils::expand_target(&target_prefix.to_path_buf())?;
+        std::mem::take(&mut self.sources)
             .into_iter()
-            .map(|source| {
+            .flat_map(|source| {
                 source
                     .strip_prefix(&self.module_path)
                     .map(|stripped| stripped.to_owned())
                     .map(|stripped| (source, stripped))
             })
-            .flatten()
-            .flat_map(|(source, source_stripped)| {
-                let resolved_target = target_prefix.join(source_stripped);
-                utils::ResolvedLink::new(&source, &resolved_target)
+            .map(|(source, source_stripped)| {
+                let resolved_target = target_prefix.join(self.renamed(source_stripped));
+                let link = utils::ResolvedLink::new(&source, &resolved_target)?;
+                if !link.abs_target.starts_with(&abs_target_prefix) {
+                    anyhow::bail!(
+                        "the target {:?} of {:?} escapes the module target {:?}",
+                        link.abs_target,
+                        source,
+                        abs_target_prefix
+                    );
+                }
+                Ok(link)
             })
-            .collect_vec()
+            .collect()
+    }
+
+    /// Applies the rename whose path is the longest ancestor of the given relative target.
+    fn renamed(&self, relative_target: PathBuf) -> PathBuf {
+        self.renames
+            .iter()
+            .filter_map(|(from, to)| {
+                let rest = relative_target.strip_prefix(from).ok()?;
+                Some((from.components().count(), to.join(rest)))
+            })
+            .max_by_key(|(depth, _)| *depth)
+            .map(|(_, renamed)| {
+                log::trace!("Renamed {:?} to {:?}", relative_target, renamed);
+                renamed
+            })
+            .unwrap_or(relative_target)
+    }
+}
+
+#[derive(Clone, Debug)]
+enum DirectivePath<'a> {
+    /// Set by a marker file, matches the exact directory containing the marker.
+    Exact(&'a PathBuf),
+    /// Set by the configuration, matches the paths that satisfy the pattern.
+    /// The pattern is tried against both the path and the path relative to the module source.
+    Pattern(glob::Pattern, &'a PathBuf),
+}
+
+impl<'a> DirectivePath<'a> {
+    fn matches(&self, path: &Path) -> bool {
+        match self {
+            DirectivePath::Exact(p) => *p == path,
+            DirectivePath::Pattern(pattern, module_path) => {
+                let options = glob::MatchOptions {
+                    require_literal_separator: true,
+                    ..Default::default()
+                };
+                pattern.matches_path_with(path, options)
+                    || path
+                        .strip_prefix(module_path)
+                        .is_ok_and(|stripped| pattern.matches_path_with(stripped, options))
+            }
+        }
     }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+// The variants are named after the directives.
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Debug)]
 enum TraversalDirective<'a> {
-    LinkThis(&'a PathBuf),
-    LinkThese(&'a PathBuf),
+    LinkThis(DirectivePath<'a>),
+    LinkThese(DirectivePath<'a>),
+    LinkTheseRecursive(DirectivePath<'a>),
+}
+
+impl<'a> TraversalDirective<'a> {
+    fn is_linkthis(&self, path: &Path) -> bool {
+        matches!(self, TraversalDirective::LinkThis(p) if p.matches(path))
+    }
+
+    fn is_linkthese(&self, path: &Path) -> bool {
+        matches!(self, TraversalDirective::LinkThese(p) if p.matches(path))
+    }
+
+    fn is_linkthese_recursive(&self, path: &Path) -> bool {
+        matches!(self, TraversalDirective::LinkTheseRecursive(p) if p.matches(path))
+    }
+}
+
+/// A pattern read from a `.dullignore` file.
+#[derive(Clone, Debug)]
+struct IgnorePattern {
+    pattern: glob::Pattern,
+    /// The directory containing the `.dullignore` file.
+    base_dir: PathBuf,
+    /// Set if the pattern has a slash, in which case it is matched against the path relative to the
+    /// base directory instead of the file name.
+    anchored: bool,
+    /// Set if the pattern ends with a slash, in which case it only matches directories.
+    dir_only: bool,
+}
+
+impl IgnorePattern {
+    /// Reads the patterns from the `.dullignore` file in the given directory, if there is one.
+    /// Empty lines and the lines starting with `#` are skipped.
+    fn read_all(dir: &Path) -> anyhow::Result<Vec<Self>> {
+        let ignore_file = dir.join(globals::DULLIGNORE_FILENAME);
+        if !ignore_file.is_file() {
+            return Ok(vec![]);
+        }
+        let contents = std::fs::read_to_string(&ignore_file)
+            .context(format!("could not read the ignore file {:?}", ignore_file))?;
+        contents
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let dir_only = line.ends_with('/');
+                let line = line.trim_end_matches('/');
+                let anchored = line.contains('/');
+                let pattern = glob::Pattern::new(line.trim_start_matches('/')).context(format!(
+                    "invalid pattern {:?} in the ignore file {:?}",
+                    line, ignore_file
+                ))?;
+                Ok(IgnorePattern {
+                    pattern,
+                    base_dir: dir.to_path_buf(),
+                    anchored,
+                    dir_only,
+                })
+            })
+            .collect()
+    }
+
+    fn matches(&self, path: &Path) -> bool {
+        if self.dir_only && !path.is_dir() {
+            return false;
+        }
+        let options = glob::MatchOptions {
+            require_literal_separator: true,
+            ..Default::default()
+        };
+        if self.anchored {
+            path.strip_prefix(&self.base_dir)
+                .is_ok_and(|stripped| self.pattern.matches_path_with(stripped, options))
+        } else {
+            path.file_name().is_some_and(|file_name| {
+                self.pattern
+                    .matches_with(&file_name.to_string_lossy(), options)
+            })
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 enum TraversalStrategy {
     LinkThis(PathBuf),
     LinkThese(Vec<PathBuf>),
+    /// Recurse into the directory while ignoring the directives under it.
+    LinkTheseRecursive(Vec<PathBuf>),
     Recurse(Vec<PathBuf>),
     Skip,
 }
 
 impl TraversalStrategy {
     /// Consumes the given path and returns the traversal strategy associated with it.
+    /// The directory contents that match
+    /// one of the `ignore_patterns` are left out. The `directives` are not considered if
+    /// `ignore_directives` is set, i.e., the path is under a `linkthese_recursive` directory.
     fn try_determine(
         path: PathBuf,
         directives: &[TraversalDirective],
-        ignore_filenames: &[&str],
+        ignore_directives: bool,
+        ignore_filenames: &[String],
+        ignore_patterns: &[IgnorePattern],
     ) -> anyhow::Result<Self> {
         if !path.try_exists().is_ok_and(|exists| exists) {
             anyhow::bail!("unreachable path {:?}", path);
         }
-        if ignore_filenames.contains(
-            &path
-                .file_name()
-                .unwrap_or_default()
-                .to_string_lossy()
-                .as_ref(),
-        ) {
+        if utils::is_ignored(&path, ignore_filenames) {
             return Ok(TraversalStrategy::Skip);
         }
         // A file must always be linked directly.
@@ -72,7 +217,8 @@ impl TraversalStrategy {
             return Ok(TraversalStrategy::LinkThis(path));
         }
         // A directory that should be treated as a file.
-        let linkthis_directive = directives.contains(&TraversalDirective::LinkThis(&path));
+        let linkthis_directive =
+            !ignore_directives && directives.iter().any(|d| d.is_linkthis(&path));
         if linkthis_directive {
             return Ok(TraversalStrategy::LinkThis(path));
         }
@@ -82,40 +228,72 @@ impl TraversalStrategy {
             .context(format!("could not read the directory contents {:?}", path))?
             .flatten()
             .map(|f| f.path())
-            .filter(|p| {
-                !ignore_filenames
-                    .contains(&p.file_name().unwrap_or_default().to_string_lossy().as_ref())
-            })
+            .filter(|p| !utils::is_ignored(p, ignore_filenames))
+            .filter(|p| !ignore_patterns.iter().any(|pattern| pattern.matches(p)))
+            // The directory entries are yielded in an arbitrary order.
+            .sorted()
             .collect_vec();
         // A directory can be either traversed recursively or not.
-        let linkthese_directive = directives.contains(&TraversalDirective::LinkThese(&path));
+        if ignore_directives {
+            return Ok(TraversalStrategy::Recurse(dir_contents));
+        }
+        let linkthese_directive = directives.iter().any(|d| d.is_linkthese(&path));
         if linkthese_directive {
             return Ok(TraversalStrategy::LinkThese(dir_contents));
         }
-        return Ok(TraversalStrategy::Recurse(dir_contents));
+        let linkthese_recursive_directive =
+            directives.iter().any(|d| d.is_linkthese_recursive(&path));
+        if linkthese_recursive_directive {
+            return Ok(TraversalStrategy::LinkTheseRecursive(dir_contents));
+        }
+        Ok(TraversalStrategy::Recurse(dir_contents))
     }
 }
 
+/// Parses a directive path given in the configuration as a glob pattern.
+fn config_pattern(path: &Path) -> anyhow::Result<glob::Pattern> {
+    glob::Pattern::new(&path.to_string_lossy())
+        .context(format!("invalid directive pattern {:?}", path))
+}
+
 #[derive(Debug)]
 pub struct ModuleParser<'a> {
     module_config: &'a ModuleConfig,
+    global_config: &'a GlobalConfig,
+    ignore_filenames: &'a [String],
 }
 
 impl<'a> ModuleParser<'a> {
-    pub fn from_config(module_config: &'a ModuleConfig) -> Self {
-        Self { module_config }
+    pub fn from_config(
+        module_config: &'a ModuleConfig,
+        global_config: &'a GlobalConfig,
+        ignore_filenames: &'a [String],
+    ) -> Self {
+        Self {
+            module_config,
+            global_config,
+            ignore_filenames,
+        }
     }
 
     pub fn parse(self) -> anyhow::Result<Module> {
         let source = &self.module_config.source;
-        println!("Parsing module {:?}", source);
         if !source.is_dir() {
-            anyhow::bail!(
-                "module path {:?} is not a directory",
-                self.module_config.source
-            );
+            return Err(DullError::NotADirectory(self.module_config.source.clone()).into());
+        }
+        // The symlinked directories are traversed, which never ends if one leads back to its ancestor.
+        if let Some((link, resolved)) = utils::find_symlink_cycle(source) {
+            return Err(DullError::SymlinkCycle {
+                path: source.clone(),
+                link,
+                resolved,
+            }
+            .into());
         }
+        let follow_links = self.module_config.follow_links;
         let all_files = WalkDir::new(source)
+            .follow_links(follow_links)
+            .sort_by_file_name()
             .into_iter()
             .flatten()
             .flat_map(|dir_entry| {
@@ -129,38 +307,56 @@ impl<'a> ModuleParser<'a> {
             .iter()
             .flat_map(|(parent, file)| {
                 let file_name = file.file_name()?.to_string_lossy();
-                if file_name == globals::LINKTHIS_FILENAME {
-                    Some(TraversalDirective::LinkThis(parent))
-                } else if file_name == globals::LINKTHESE_FILENAME {
-                    Some(TraversalDirective::LinkThese(parent))
+                if file_name == self.global_config.linkthis_file {
+                    Some(TraversalDirective::LinkThis(DirectivePath::Exact(parent)))
+                } else if file_name == self.global_config.linkthese_file {
+                    Some(TraversalDirective::LinkThese(DirectivePath::Exact(parent)))
+                } else if file_name == self.global_config.linkthese_recursive_file {
+                    Some(TraversalDirective::LinkTheseRecursive(
+                        DirectivePath::Exact(parent),
+                    ))
                 } else {
                     None
                 }
             })
             .collect_vec();
         // Extend the directives with the ones from the configuration.
-        directives.extend(
-            self.module_config
-                .linkthis
-                .iter()
-                .map(|p| TraversalDirective::LinkThis(p)),
-        );
-        directives.extend(
-            self.module_config
-                .linkthese
-                .iter()
-                .map(|p| TraversalDirective::LinkThese(p)),
-        );
+        for linkthis in self.module_config.linkthis.iter() {
+            let pattern = config_pattern(linkthis)?;
+            directives.push(TraversalDirective::LinkThis(DirectivePath::Pattern(
+                pattern, source,
+            )));
+        }
+        for linkthese in self.module_config.linkthese.iter() {
+            let pattern = config_pattern(linkthese)?;
+            directives.push(TraversalDirective::LinkThese(DirectivePath::Pattern(
+                pattern, source,
+            )));
+        }
+        for linkthese_recursive in self.module_config.linkthese_recursive.iter() {
+            let pattern = config_pattern(linkthese_recursive)?;
+            directives.push(TraversalDirective::LinkTheseRecursive(
+                DirectivePath::Pattern(pattern, source),
+            ));
+        }
         // In order to get all the paths that are exposed by this module, perform a breadth-first
         // traversal in the filesystem, rooted at the module folder.
         let mut collected_paths = vec![];
-        let mut frontier = vec![source.clone()];
-        while frontier.len() > 0 {
-            let curr_path = frontier.pop().expect("could not pop from the frontier");
+        let mut frontier = vec![(source.clone(), vec![], false)];
+        while let Some((curr_path, mut ignore_patterns, ignore_directives)) = frontier.pop() {
+            // The ignore patterns of a directory also apply to its subdirectories.
+            if curr_path.is_dir() {
+                match IgnorePattern::read_all(&curr_path) {
+                    Ok(patterns) => ignore_patterns.extend(patterns),
+                    Err(err) => log::warning!("Skipping the ignore file due to error: {:?}", err),
+                }
+            }
             match TraversalStrategy::try_determine(
                 curr_path.clone(),
                 &directives,
-                globals::DEFAULT_IGNOREFILES,
+                ignore_directives,
+                self.ignore_filenames,
+                &ignore_patterns,
             ) {
                 Ok(strategy) => match strategy {
                     TraversalStrategy::LinkThis(path) => {
@@ -173,23 +369,112 @@ impl<'a> ModuleParser<'a> {
                         let inner_dirs = paths.clone().into_iter().filter(|path| path.is_dir());
                         let inner_files = paths.into_iter().filter(|path| path.is_file());
                         collected_paths.extend(inner_files);
-                        frontier.extend(inner_dirs);
+                        frontier.extend(
+                            inner_dirs.map(|dir| (dir, ignore_patterns.clone(), ignore_directives)),
+                        );
+                    }
+                    TraversalStrategy::LinkTheseRecursive(paths) => {
+                        let inner_dirs = paths.clone().into_iter().filter(|path| path.is_dir());
+                        let inner_files = paths.into_iter().filter(|path| path.is_file());
+                        collected_paths.extend(inner_files);
+                        frontier.extend(inner_dirs.map(|dir| (dir, ignore_patterns.clone(), true)));
                     }
                     TraversalStrategy::Skip => {
                         continue;
                     }
                 },
                 Err(err) => {
-                    println!(
+                    log::warning!(
                         "Skipping traversing {:?} due to error: {:?}",
-                        curr_path, err
+                        curr_path,
+                        err
                     )
                 }
             }
         }
+        // Sort the paths so that the builds are reproducible.
+        collected_paths.sort();
+        log::debug!(
+            "Collected {} sources from {:?}",
+            collected_paths.len(),
+            source
+        );
+        let module_path = match self.module_config.prefix_strip {
+            PrefixStrip::Source => source.clone(),
+            PrefixStrip::Parent => {
+                if source.file_name().is_none() {
+                    anyhow::bail!("module path {:?} has no directory name to keep", source);
+                }
+                source.parent().map(Path::to_path_buf).unwrap_or_default()
+            }
+        };
         Ok(Module {
-            module_path: source.clone(),
+            module_path,
             sources: collected_paths,
+            renames: self.module_config.rename.clone(),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses the module at the given source with the default settings.
+    fn parse(source: &Path) -> Module {
+        let module_config = ModuleConfig {
+            source: source.to_path_buf(),
+            target: PathBuf::from("/target"),
+            ..Default::default()
+        };
+        let global_config = GlobalConfig::default();
+        let ignore_filenames = utils::ignore_filenames(&global_config.marker_filenames());
+        ModuleParser::from_config(&module_config, &global_config, &ignore_filenames)
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn parses_in_a_stable_order() {
+        let source = utils::test_dir();
+        // Create the entries out of order so that the directory order is unlikely to be sorted.
+        for name in ["zeta", "alpha", "mid", "beta"] {
+            std::fs::create_dir_all(source.join(name).join("nested")).unwrap();
+            for file in ["z", "a", "m"] {
+                std::fs::write(source.join(name).join(file), "").unwrap();
+                std::fs::write(source.join(name).join("nested").join(file), "").unwrap();
+            }
+        }
+        std::fs::write(source.join("mid").join(globals::LINKTHIS_FILENAME), "").unwrap();
+        let first = parse(&source).sources;
+        let second = parse(&source).sources;
+        assert_eq!(first, second);
+        assert!(first.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(first.contains(&source.join("mid")));
+    }
+
+    #[test]
+    fn traverses_symlinked_dirs() {
+        let source = utils::test_dir();
+        std::fs::create_dir(source.join("real")).unwrap();
+        std::fs::write(source.join("real/file"), "").unwrap();
+        std::os::unix::fs::symlink("real", source.join("shared")).unwrap();
+        let sources = parse(&source).sources;
+        assert_eq!(
+            sources,
+            vec![source.join("real/file"), source.join("shared/file")]
+        );
+        // A link back to the module would be traversed forever.
+        std::os::unix::fs::symlink(".", source.join("real/loop")).unwrap();
+        let module_config = ModuleConfig {
+            source: source.to_path_buf(),
+            ..Default::default()
+        };
+        let global_config = GlobalConfig::default();
+        let result = ModuleParser::from_config(&module_config, &global_config, &[]).parse();
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<DullError>(),
+            Some(DullError::SymlinkCycle { .. })
+        ));
+    }
+}
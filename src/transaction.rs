@@ -1,26 +1,86 @@
+use crate::{globals, utils};
 use anyhow::Context;
 use primitives::*;
 use rand::Rng;
-use std::path::PathBuf;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
 mod primitives;
 mod tx_apply;
 mod tx_builder;
 mod tx_gen;
+mod tx_log;
 mod tx_processor;
 mod tx_result;
+mod tx_summary;
 
 pub use tx_apply::*;
 pub use tx_builder::*;
 pub use tx_gen::*;
+pub use tx_log::*;
 pub use tx_processor::*;
 pub use tx_result::*;
+pub use tx_summary::*;
+
+/// Returns the size of the file or the total size of the directory at the given path.
+fn size_of(path: &Path) -> u64 {
+    match path.symlink_metadata() {
+        Ok(metadata) if metadata.is_dir() => utils::dir_size(path),
+        Ok(metadata) => metadata.len(),
+        Err(_) => 0,
+    }
+}
+
+/// Tracks the paths that a shell script creates and removes across its transactions.
+#[derive(Debug, Default)]
+pub struct ScriptPaths {
+    /// Maps the paths to the index of the latest command that created or removed them, along with
+    /// whether it created an empty directory, or `None` if it removed the path.
+    events: HashMap<PathBuf, (usize, Option<bool>)>,
+    num_events: usize,
+}
+
+impl ScriptPaths {
+    /// Records the effect of the primitive on its target.
+    fn record(&mut self, primitive: &FsPrimitive, target: &Path) {
+        let event = if primitive.creates() {
+            Some(matches!(primitive, FsPrimitive::CreateDir(..)))
+        } else {
+            None
+        };
+        self.events
+            .insert(target.to_path_buf(), (self.num_events, event));
+        self.num_events += 1;
+    }
+
+    /// Returns true iff the path is missing after the recorded commands, i.e., the latest command on
+    /// it or its ancestors removed them, or created an empty directory above it.
+    fn is_removed(&self, path: &Path) -> bool {
+        let latest = path
+            .ancestors()
+            .flat_map(|ancestor| Some((ancestor, self.events.get(ancestor)?)))
+            .max_by_key(|(_, (index, _))| *index);
+        match latest {
+            Some((_, (_, None))) => true,
+            Some((ancestor, (_, Some(true)))) => ancestor != path,
+            _ => false,
+        }
+    }
+}
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct Transaction {
     id: String,
     name: String,
+    /// Human readable description of hand-authored transactions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
     backup_dir: PathBuf,
+    /// If set, the removed paths are not backed up, so the removals cannot be rolled back.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    no_backup: bool,
     primitives: Vec<FsPrimitive>,
 }
 
@@ -30,15 +90,18 @@ impl Transaction {
         // Create a random transaction id.
         let id = format!("{}-{}", name, rand::thread_rng().gen::<u32>());
         // Create a backup directory for the transaction.
-        let backup_dir = PathBuf::from("transactions").join(&id);
-        std::fs::create_dir_all(&backup_dir)
-            .context(format!("could not create the backup directory"))?;
+        let backup_dir = globals::transactions_dir()
+            .join(utils::date_name())
+            .join(&id);
+        std::fs::create_dir_all(&backup_dir).context("could not create the backup directory")?;
         let tx_file_path = backup_dir.join("tx");
         // Construct the concrete transaction.
         let concrete_tx = Transaction {
             id,
             backup_dir,
             name,
+            description: None,
+            no_backup: false,
             primitives,
         };
         // Write it into a file.
@@ -55,6 +118,228 @@ impl Transaction {
         Ok(concrete_tx)
     }
 
+    /// Returns the directory that holds the backups and the file of this transaction.
+    pub fn backup_dir(&self) -> &PathBuf {
+        &self.backup_dir
+    }
+
+    /// Disables the backups of the removed paths, which makes the removals faster but irreversible.
+    pub fn without_backups(self) -> Self {
+        Self {
+            no_backup: true,
+            ..self
+        }
+    }
+
+    /// Returns the unique id of the transaction.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Returns the number of primitives in the transaction.
+    pub fn num_primitives(&self) -> usize {
+        self.primitives.len()
+    }
+
+    /// Returns the name of the transaction.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the description of the transaction, if it has one.
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    /// Checks that no two primitives create or remove the same path without the path being
+    /// removed or created in between.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let mut last_primitives: HashMap<&PathBuf, &FsPrimitive> = HashMap::new();
+        for primitive in self.primitives.iter() {
+            let Some(target) = primitive.target() else {
+                continue;
+            };
+            if let Some(last_primitive) = last_primitives.get(target) {
+                if last_primitive.creates() == primitive.creates() {
+                    anyhow::bail!(
+                        "conflicting primitives in transaction {}: `{}` and `{}`",
+                        self.id,
+                        last_primitive,
+                        primitive
+                    );
+                }
+            }
+            last_primitives.insert(target, primitive);
+        }
+        Ok(())
+    }
+
+    /// Combines the primitives of the two transactions into a single transaction under the name of
+    /// `self`, so that they are run and rolled back as one. The primitives are reordered through a
+    /// builder, so that the directories are created before their contents and removed after them.
+    /// Fails if the transactions are the same, if they both create a path differently, or if one
+    /// creates a path that the other removes.
+    pub fn merge(self, other: Transaction) -> anyhow::Result<Transaction> {
+        if self.backup_dir == other.backup_dir {
+            anyhow::bail!(
+                "cannot merge the transaction {} with itself, they share the backup directory {:?}",
+                self.id,
+                self.backup_dir
+            );
+        }
+        // The builder keeps only one of the creations of a path, so the differing ones are rejected here.
+        let mut created: HashMap<&PathBuf, &FsPrimitive> = HashMap::new();
+        for primitive in self.primitives.iter().chain(other.primitives.iter()) {
+            let Some(target) = primitive.target().filter(|_| primitive.creates()) else {
+                continue;
+            };
+            match created.insert(target, primitive) {
+                Some(previous) if previous != primitive => anyhow::bail!(
+                    "cannot merge the transactions {} and {}, `{}` conflicts with `{}`",
+                    self.id,
+                    other.id,
+                    primitive,
+                    previous
+                ),
+                _ => {}
+            }
+        }
+        let mut txb = TxBuilder::empty();
+        for primitive in self.primitives.into_iter().chain(other.primitives) {
+            txb.push(primitive);
+        }
+        let merged = txb.build(self.name.clone()).context(format!(
+            "could not merge the transactions {} and {}",
+            self.id, other.id
+        ))?;
+        // The original transactions will never be run, so their directories are not needed.
+        for backup_dir in [&self.backup_dir, &other.backup_dir] {
+            std::fs::remove_dir_all(backup_dir).context(format!(
+                "could not remove the transaction directory {:?}",
+                backup_dir
+            ))?;
+        }
+        // The removals are backed up unless both transactions disable the backups.
+        Ok(if self.no_backup && other.no_backup {
+            merged.without_backups()
+        } else {
+            merged
+        })
+    }
+
+    /// Returns an estimate of the bytes this transaction writes to the targets, i.e., the size of
+    /// the copied sources.
+    pub fn estimated_copy_bytes(&self) -> u64 {
+        self.primitives
+            .iter()
+            .map(|p| match p {
+                FsPrimitive::CopyFile { source, .. } | FsPrimitive::CopyDir { source, .. } => {
+                    size_of(source)
+                }
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// Returns an estimate of the bytes this transaction writes to the backup directory, i.e., the
+    /// size of the removed paths on the other filesystems. The removed paths on the same filesystem
+    /// are renamed into the backup directory, which takes no space.
+    pub fn estimated_backup_bytes(&self) -> u64 {
+        if self.no_backup {
+            return 0;
+        }
+        let backup_device = utils::device_of(&self.backup_dir);
+        self.primitives
+            .iter()
+            .map(|p| match p {
+                FsPrimitive::RemoveFile(target) | FsPrimitive::RemoveDirAll(target)
+                    if utils::device_of(target) != backup_device =>
+                {
+                    size_of(target)
+                }
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// Returns the shell commands that are equivalent to this transaction, in order. The removals of
+    /// the paths that the previous commands of the script already removed are left out, as removing
+    /// a missing path aborts the script.
+    pub fn to_shell_script(&self, script_paths: &mut ScriptPaths) -> String {
+        let mut script = format!("# {}\n", self.id);
+        for primitive in self.primitives.iter() {
+            if let Some(target) = primitive.target() {
+                if !primitive.creates() && script_paths.is_removed(target) {
+                    continue;
+                }
+                script_paths.record(primitive, target);
+            }
+            script.push_str(&primitive.to_shell_command());
+            script.push('\n');
+        }
+        script
+    }
+
+    /// Describes the effects of this transaction without running it, one line per primitive. The
+    /// removals are marked, as they back up the removed paths when the transaction is run atomically,
+    /// unless the backups are disabled.
+    pub fn dry_run(&self) -> Vec<String> {
+        self.primitives
+            .iter()
+            .map(|primitive| match primitive {
+                FsPrimitive::RemoveFile(_) | FsPrimitive::RemoveDirAll(_) if !self.no_backup => {
+                    format!("{} (backed up)", primitive)
+                }
+                _ => primitive.to_string(),
+            })
+            .collect()
+    }
+
+    /// Returns the backup files under `backups_root` that this transaction restores from but are missing or unreadable.
+    pub fn missing_backups(&self, backups_root: &Path) -> Vec<PathBuf> {
+        let backups_root =
+            utils::expand_path(&backups_root.to_path_buf()).unwrap_or(backups_root.to_path_buf());
+        self.primitives
+            .iter()
+            .flat_map(|p| match p {
+                FsPrimitive::CopyFile { source, .. } => Some(source),
+                FsPrimitive::CopyDir { source, .. } => Some(source),
+                FsPrimitive::Rename { from, .. } => Some(from),
+                _ => None,
+            })
+            .filter(|source| utils::expand_path(source).is_ok_and(|s| s.starts_with(&backups_root)))
+            .filter(|source| {
+                let readable = source
+                    .symlink_metadata()
+                    .is_ok_and(|m| m.is_symlink() || std::fs::File::open(source).is_ok());
+                !readable
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Records the given transaction as the undo of the transaction at the given directory.
+    fn record_undo(tx_dir: &Path, undo_tx: &Transaction) -> anyhow::Result<()> {
+        let undo_pointer_path = tx_dir.join("undo");
+        let undo_tx_path = utils::expand_path(&undo_tx.backup_dir.join("tx"))?;
+        std::fs::write(&undo_pointer_path, undo_tx_path.to_string_lossy().as_ref()).context(
+            format!(
+                "could not record the undo transaction at {:?}",
+                undo_pointer_path
+            ),
+        )
+    }
+
+    /// Reads the undo transaction that was recorded when this transaction was run.
+    pub fn read_undo(&self) -> anyhow::Result<Transaction> {
+        let undo_pointer_path = self.backup_dir.join("undo");
+        let undo_tx_path = std::fs::read_to_string(&undo_pointer_path).context(format!(
+            "could not find the undo transaction at {:?}, was the transaction run?",
+            undo_pointer_path
+        ))?;
+        Transaction::read(undo_tx_path.into())
+    }
+
     /// Reads a concrete transaction from a file.
     pub fn read(path: PathBuf) -> anyhow::Result<Self> {
         let tx_file = std::fs::File::open(&path)
@@ -66,3 +351,66 @@ impl Transaction {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a transaction out of the given primitives.
+    fn build(primitives: Vec<FsPrimitive>) -> Transaction {
+        let mut txb = TxBuilder::empty();
+        for primitive in primitives {
+            txb.push(primitive);
+        }
+        txb.build("Test").unwrap()
+    }
+
+    #[test]
+    fn merges_in_order() {
+        let _dir = utils::test_dir();
+        let link = FsPrimitive::Link {
+            original: "/source/file".into(),
+            target: "/target/dir/file".into(),
+        };
+        let first = build(vec![link.clone(), FsPrimitive::RemoveDir("/old".into())]);
+        let second = build(vec![
+            FsPrimitive::CreateDir("/target/dir".into(), None),
+            FsPrimitive::RemoveFile("/old/file".into()),
+        ]);
+        let backup_dirs = [first.backup_dir.clone(), second.backup_dir.clone()];
+        let merged = first.merge(second).unwrap();
+        assert_eq!(
+            merged.primitives,
+            vec![
+                FsPrimitive::CreateDir("/target/dir".into(), None),
+                link,
+                FsPrimitive::RemoveFile("/old/file".into()),
+                FsPrimitive::RemoveDir("/old".into()),
+            ]
+        );
+        assert!(backup_dirs.iter().all(|backup_dir| !backup_dir.exists()));
+    }
+
+    #[test]
+    fn rejects_conflicting_creations() {
+        let _dir = utils::test_dir();
+        let link = FsPrimitive::Link {
+            original: "/source/file".into(),
+            target: "/target/file".into(),
+        };
+        let copy = FsPrimitive::CopyFile {
+            source: "/source/other".into(),
+            target: "/target/file".into(),
+        };
+        assert!(build(vec![link.clone()]).merge(build(vec![copy])).is_err());
+        let removal = FsPrimitive::RemoveFile("/target/file".into());
+        assert!(build(vec![link.clone()])
+            .merge(build(vec![removal]))
+            .is_err());
+        // The same creation in both transactions is kept once.
+        let merged = build(vec![link.clone()])
+            .merge(build(vec![link.clone()]))
+            .unwrap();
+        assert_eq!(merged.primitives, vec![link]);
+    }
+}
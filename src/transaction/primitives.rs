@@ -1,17 +1,46 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use anyhow::Context;
 use rand::Rng;
 
-use crate::utils;
+use crate::{error::DullError, utils};
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
 pub(super) enum FsPrimitive {
-    Link { original: PathBuf, target: PathBuf },
-    CopyFile { source: PathBuf, target: PathBuf },
+    Link {
+        original: PathBuf,
+        target: PathBuf,
+    },
+    CopyFile {
+        source: PathBuf,
+        target: PathBuf,
+    },
+    CopyDir {
+        source: PathBuf,
+        target: PathBuf,
+        /// The file names that are left out of the copy at any depth.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        ignore: Vec<String>,
+    },
+    HardLink {
+        original: PathBuf,
+        target: PathBuf,
+    },
+    Rename {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    /// Sets the permission bits of the file at the target, following symlinks.
+    SetMode {
+        target: PathBuf,
+        mode: u32,
+    },
     RemoveFile(PathBuf),
     RemoveDir(PathBuf),
-    CreateDir(PathBuf),
+    RemoveDirAll(PathBuf),
+    /// Creates a directory with the given permission bits, or the default ones if unspecified.
+    #[serde(deserialize_with = "deserialize_create_dir")]
+    CreateDir(PathBuf, Option<u32>),
     Nop,
 }
 
@@ -28,73 +57,358 @@ impl std::fmt::Display for FsPrimitive {
                 source.display(),
                 target.display()
             )),
+            FsPrimitive::CopyDir { source, target, .. } => f.write_fmt(format_args!(
+                "CopyDir {} => {}",
+                source.display(),
+                target.display()
+            )),
+            FsPrimitive::HardLink { original, target } => f.write_fmt(format_args!(
+                "HardLink {} <= {}",
+                original.display(),
+                target.display()
+            )),
+            FsPrimitive::Rename { from, to } => f.write_fmt(format_args!(
+                "Rename {} => {}",
+                from.display(),
+                to.display()
+            )),
+            FsPrimitive::SetMode { target, mode } => {
+                f.write_fmt(format_args!("SetMode {:o} {}", mode, target.display()))
+            }
             FsPrimitive::RemoveFile(path) => {
                 f.write_fmt(format_args!("RemoveFile {}", path.display()))
             }
+            FsPrimitive::RemoveDirAll(path) => {
+                f.write_fmt(format_args!("RemoveDirAll {}", path.display()))
+            }
             FsPrimitive::RemoveDir(path) => {
                 f.write_fmt(format_args!("RemoveDir {}", path.display()))
             }
-            FsPrimitive::CreateDir(path) => {
+            FsPrimitive::CreateDir(path, None) => {
                 f.write_fmt(format_args!("CreateDir {}", path.display()))
             }
+            FsPrimitive::CreateDir(path, Some(mode)) => {
+                f.write_fmt(format_args!("CreateDir {:o} {}", mode, path.display()))
+            }
             FsPrimitive::Nop => f.write_fmt(format_args!("Nop")),
         }
     }
 }
 
+/// Deserializes the fields of a `CreateDir` primitive, including the ones recorded before the mode was added.
+fn deserialize_create_dir<'de, D>(deserializer: D) -> Result<(PathBuf, Option<u32>), D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum CreateDirFields {
+        WithMode(PathBuf, Option<u32>),
+        Legacy(PathBuf),
+    }
+    Ok(
+        match <CreateDirFields as serde::Deserialize>::deserialize(deserializer)? {
+            CreateDirFields::WithMode(path, mode) => (path, mode),
+            CreateDirFields::Legacy(path) => (path, None),
+        },
+    )
+}
+
+/// Quotes the given path for a POSIX shell.
+fn shell_escape(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\'', "'\\''"))
+}
+
+/// Returns the shell commands that copy the file or symlink at `source` to `target` the way
+/// `utils::copy_file_or_symlink` does, i.e., the symlinks are linked to their resolved paths, and the
+/// regular files keep their permission bits.
+fn copy_commands(source: &Path, target: &Path) -> Vec<String> {
+    if source.is_symlink() {
+        let canon_source = source
+            .canonicalize()
+            .or_else(|_| source.read_link())
+            .unwrap_or(source.to_path_buf());
+        return vec![format!(
+            "ln -s {} {}",
+            shell_escape(&canon_source),
+            shell_escape(target)
+        )];
+    }
+    let mut commands = vec![format!(
+        "cp {} {}",
+        shell_escape(source),
+        shell_escape(target)
+    )];
+    if let Ok(metadata) = source.metadata() {
+        use std::os::unix::fs::PermissionsExt;
+        commands.push(format!(
+            "chmod {:o} {}",
+            metadata.permissions().mode() & 0o7777,
+            shell_escape(target)
+        ));
+    }
+    if crate::globals::preserve_timestamps() {
+        commands.push(format!(
+            "touch -r {} {}",
+            shell_escape(source),
+            shell_escape(target)
+        ));
+    }
+    commands
+}
+
+/// Returns true iff the error is caused by an io error due to the missing permissions.
+fn is_permission_denied(err: &anyhow::Error) -> bool {
+    err.chain()
+        .flat_map(|cause| cause.downcast_ref::<std::io::Error>())
+        .any(|io_err| io_err.kind() == std::io::ErrorKind::PermissionDenied)
+}
+
 impl FsPrimitive {
+    /// Returns the path that is created or removed by the primitive.
+    /// Primitives that only modify an existing path have none.
+    pub(super) fn target(&self) -> Option<&PathBuf> {
+        match self {
+            FsPrimitive::Link { target, .. }
+            | FsPrimitive::CopyFile { target, .. }
+            | FsPrimitive::CopyDir { target, .. }
+            | FsPrimitive::HardLink { target, .. }
+            | FsPrimitive::Rename { to: target, .. } => Some(target),
+            FsPrimitive::RemoveFile(path)
+            | FsPrimitive::RemoveDir(path)
+            | FsPrimitive::RemoveDirAll(path)
+            | FsPrimitive::CreateDir(path, _) => Some(path),
+            FsPrimitive::SetMode { .. } | FsPrimitive::Nop => None,
+        }
+    }
+
+    /// Returns true iff the primitive creates its target, rather than removing it.
+    pub(super) fn creates(&self) -> bool {
+        matches!(
+            self,
+            FsPrimitive::Link { .. }
+                | FsPrimitive::CopyFile { .. }
+                | FsPrimitive::CopyDir { .. }
+                | FsPrimitive::HardLink { .. }
+                | FsPrimitive::Rename { .. }
+                | FsPrimitive::CreateDir(..)
+        )
+    }
+
+    /// Returns the shell command that is equivalent to applying the primitive.
+    pub(super) fn to_shell_command(&self) -> String {
+        match self {
+            FsPrimitive::Link { original, target } => {
+                format!("ln -s {} {}", shell_escape(original), shell_escape(target))
+            }
+            FsPrimitive::CopyFile { source, target } => copy_commands(source, target).join("\n"),
+            // Expanded like `utils::copy_dir_all`, so that the inner symlinks are resolved alike.
+            FsPrimitive::CopyDir {
+                source,
+                target,
+                ignore,
+            } => walkdir::WalkDir::new(source)
+                .follow_root_links(true)
+                .follow_links(false)
+                .into_iter()
+                .filter_entry(|entry| {
+                    entry.depth() == 0 || !utils::is_ignored(entry.path(), ignore)
+                })
+                .flatten()
+                .flat_map(|entry| {
+                    let inner_source = entry.path();
+                    let inner_target = match inner_source.strip_prefix(source) {
+                        Ok(rest) if !rest.as_os_str().is_empty() => target.join(rest),
+                        _ => target.clone(),
+                    };
+                    if entry.depth() == 0 || (inner_source.is_dir() && !inner_source.is_symlink()) {
+                        vec![format!("mkdir {}", shell_escape(&inner_target))]
+                    } else {
+                        copy_commands(inner_source, &inner_target)
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+            FsPrimitive::HardLink { original, target } => {
+                format!("ln {} {}", shell_escape(original), shell_escape(target))
+            }
+            FsPrimitive::Rename { from, to } => {
+                format!("mv {} {}", shell_escape(from), shell_escape(to))
+            }
+            FsPrimitive::RemoveFile(path) => format!("rm {}", shell_escape(path)),
+            FsPrimitive::SetMode { target, mode } => {
+                format!("chmod {:o} {}", mode, shell_escape(target))
+            }
+            FsPrimitive::RemoveDir(path) => format!("rmdir {}", shell_escape(path)),
+            FsPrimitive::RemoveDirAll(path) => format!("rm -r {}", shell_escape(path)),
+            FsPrimitive::CreateDir(path, None) => format!("mkdir -p {}", shell_escape(path)),
+            FsPrimitive::CreateDir(path, Some(mode)) => {
+                format!("mkdir -p -m {:o} {}", mode, shell_escape(path))
+            }
+            FsPrimitive::Nop => ":".to_string(),
+        }
+    }
+
     /// Applies the primitive, modifying the filesystem. Returns the inverse primitive which restores the filesystem to its previous state.
     pub(super) fn apply(self, backup_dir: Option<&PathBuf>) -> anyhow::Result<FsPrimitive> {
+        let path = match &self {
+            FsPrimitive::SetMode { target, .. } => Some(target.clone()),
+            primitive => primitive.target().cloned(),
+        };
+        self.apply_unchecked(backup_dir).map_err(|err| match path {
+            Some(path) if is_permission_denied(&err) => {
+                err.context(DullError::PermissionDenied(path))
+            }
+            _ => err,
+        })
+    }
+
+    fn apply_unchecked(self, backup_dir: Option<&PathBuf>) -> anyhow::Result<FsPrimitive> {
         let backup_name = format!("{}", rand::thread_rng().gen::<u32>());
         match self {
             FsPrimitive::Link { original, target } => {
-                std::os::unix::fs::symlink(&original, &target)
-                    .context(format!("could not link {:?} to {:?}", target, original,))?;
+                match std::os::unix::fs::symlink(&original, &target) {
+                    Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                        return Err(DullError::TargetExists(target).into());
+                    }
+                    res => {
+                        res.context(format!("could not link {:?} to {:?}", target, original,))?
+                    }
+                }
                 Ok(Self::RemoveFile(target))
             }
             FsPrimitive::CopyFile { source, target } => {
-                if let Ok(_) = std::fs::symlink_metadata(&target) {
-                    anyhow::bail!("file at {:?} already exists", target);
+                if std::fs::symlink_metadata(&target).is_ok() {
+                    return Err(DullError::TargetExists(target).into());
                 }
-                utils::copy_file_or_symlink(&source, &target).context(format!(
+                utils::copy_file_or_symlink(
+                    &source,
+                    &target,
+                    crate::globals::preserve_timestamps(),
+                )
+                .context(format!(
                     "could not copy the file/symlink {:?} to {:?}",
                     source, target
                 ))?;
+                // Explicitly carry over the permissions of regular files, e.g., the executable bit.
+                utils::copy_permissions(&source, &target)?;
+                Ok(Self::RemoveFile(target))
+            }
+            FsPrimitive::CopyDir {
+                source,
+                target,
+                ignore,
+            } => {
+                utils::copy_dir_all(&source, &target, &ignore).context(format!(
+                    "could not copy the directory {:?} to {:?}",
+                    source, target
+                ))?;
+                Ok(Self::RemoveDirAll(target))
+            }
+            FsPrimitive::HardLink { original, target } => {
+                utils::hard_link_or_copy(&original, &target)?;
                 Ok(Self::RemoveFile(target))
             }
+            FsPrimitive::Rename { from, to } => {
+                utils::move_path(&from, &to)
+                    .context(format!("could not move {:?} to {:?}", from, to))?;
+                Ok(Self::Rename { from: to, to: from })
+            }
+            FsPrimitive::SetMode { target, mode } => {
+                use std::os::unix::fs::PermissionsExt;
+                let old_mode = std::fs::metadata(&target)
+                    .context(format!("could not read the metadata of {:?}", target))?
+                    .permissions()
+                    .mode()
+                    & 0o7777;
+                std::fs::set_permissions(&target, std::fs::Permissions::from_mode(mode))
+                    .context(format!("could not set the mode of {:?}", target))?;
+                Ok(Self::SetMode {
+                    target,
+                    mode: old_mode,
+                })
+            }
             FsPrimitive::RemoveFile(path) => {
-                let undo_mod = if let Some(backup_dir) = backup_dir {
+                if let Some(backup_dir) = backup_dir {
+                    // Back up by moving the file, which is cheap on the same filesystem.
+                    let backup = backup_dir.join(backup_name);
+                    Self::Rename {
+                        from: path,
+                        to: backup,
+                    }
+                    .apply(None)
+                    .context("could not backup the file")
+                } else {
+                    // Cannot possibly undo a removal if we are not being supplied a backup directory.
+                    std::fs::remove_file(&path)
+                        .context(format!("could not remove file {:?}", path))?;
+                    Ok(Self::Nop)
+                }
+            }
+            FsPrimitive::RemoveDirAll(path) => {
+                if let Some(backup_dir) = backup_dir {
+                    // Back up by moving the directory, which is cheap on the same filesystem.
                     let backup = backup_dir.join(backup_name);
-                    utils::copy_file_or_symlink(&path, &backup)
-                        .context(format!("could not backup {:?} to {:?}", path, backup))?;
-                    Self::CopyFile {
-                        source: backup,
-                        target: path.clone(),
+                    Self::Rename {
+                        from: path,
+                        to: backup,
                     }
+                    .apply(None)
+                    .context("could not backup the directory")
                 } else {
                     // Cannot possibly undo a removal if we are not being supplied a backup directory.
-                    Self::Nop
-                };
-                std::fs::remove_file(&path).context("could not remove file {:?}")?;
-                Ok(undo_mod)
+                    std::fs::remove_dir_all(&path)
+                        .context(format!("could not remove {:?}", path))?;
+                    Ok(Self::Nop)
+                }
             }
-            FsPrimitive::CreateDir(path) => {
+            FsPrimitive::CreateDir(path, mode) => {
                 let path_exists = path.symlink_metadata().is_ok();
                 if path_exists {
-                    anyhow::bail!("{:?} already exists", path);
+                    return Err(DullError::TargetExists(path).into());
                 }
                 std::fs::create_dir(&path).context(format!("could not create {:?}", path))?;
+                if let Some(mode) = mode {
+                    use std::os::unix::fs::PermissionsExt;
+                    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode))
+                        .context(format!("could not set the mode of {:?}", path))?;
+                }
                 Ok(Self::RemoveDir(path))
             }
             FsPrimitive::RemoveDir(path) => {
                 let path_exists = path.symlink_metadata().is_ok();
                 if !path_exists {
-                    anyhow::bail!("{:?} doesn't exist", path);
+                    return Err(DullError::SourceMissing(path).into());
                 }
                 std::fs::remove_dir(&path).context(format!("could not remove {:?}", path))?;
-                Ok(Self::CreateDir(path))
+                Ok(Self::CreateDir(path, None))
             }
             FsPrimitive::Nop => Ok(FsPrimitive::Nop),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    #[test]
+    fn copy_file_keeps_mode() {
+        let dir = utils::test_dir();
+        let source = dir.join("script.sh");
+        let target = dir.join("copied.sh");
+        std::fs::write(&source, "#!/bin/sh").unwrap();
+        std::fs::set_permissions(&source, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let inverse = FsPrimitive::CopyFile {
+            source,
+            target: target.clone(),
+        }
+        .apply(None)
+        .unwrap();
+        assert_eq!(inverse, FsPrimitive::RemoveFile(target.clone()));
+        let mode = target.metadata().unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o755);
+    }
+}
@@ -1,23 +1,250 @@
-use std::path::PathBuf;
+use std::{io::Write, path::PathBuf, time::Duration};
 
-use crate::transaction::TxBuilder;
+use itertools::Itertools;
+
+use crate::{globals, log, transaction::TxBuilder, utils};
 
 use super::{FsPrimitive, Transaction, TxResult};
 
+/// Maximum number of independent primitives that are applied in parallel at once.
+const MAX_BATCH_SIZE: usize = 512;
+
+/// Denotes how the primitives are displayed while they are being applied.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DisplayMode {
+    /// Nothing is displayed.
+    Silent,
+    /// Each primitive is displayed on its own line.
+    PerLine,
+    /// A single progress line is displayed and updated.
+    Progress,
+}
+
+impl DisplayMode {
+    /// Returns the display mode selected by the command line flags. The primitives are displayed
+    /// from verbosity 2 on.
+    pub fn from_flags(verbosity: u8, progress: bool) -> Self {
+        if progress {
+            DisplayMode::Progress
+        } else if verbosity >= 2 {
+            DisplayMode::PerLine
+        } else {
+            DisplayMode::Silent
+        }
+    }
+}
+
+/// Prints the progress line, replacing the previous one.
+fn print_progress(icon: &str, done: usize, total: usize, current: &str) {
+    // Keep the line short so that it does not wrap.
+    let current: String = current.chars().take(60).collect();
+    print!("\r\x1b[2K {} {}/{} {}", icon, done, total, current);
+    let _ = std::io::stdout().flush();
+}
+
+/// Returns true iff the error is caused by a transient io error, e.g., on a networked filesystem.
+/// Genuine conflicts, such as an existing target, are never retryable.
+fn is_retryable(err: &anyhow::Error) -> bool {
+    err.chain()
+        .flat_map(|cause| cause.downcast_ref::<std::io::Error>())
+        .any(|io_err| {
+            matches!(
+                io_err.kind(),
+                std::io::ErrorKind::Interrupted
+                    | std::io::ErrorKind::WouldBlock
+                    | std::io::ErrorKind::TimedOut
+            )
+        })
+}
+
+/// Applies the primitive, retrying with an exponential backoff if it fails due to a transient error.
+fn apply_with_retries(
+    primitive: FsPrimitive,
+    backup_dir: Option<&PathBuf>,
+) -> anyhow::Result<FsPrimitive> {
+    retry_transient(&primitive, globals::max_retries(), || {
+        primitive.clone().apply(backup_dir)
+    })
+}
+
+/// Runs the operation, retrying it at most `max_retries` times with an exponential backoff while it
+/// fails due to a transient error.
+fn retry_transient<T>(
+    description: &dyn std::fmt::Display,
+    max_retries: u32,
+    mut operation: impl FnMut() -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    let mut backoff = Duration::from_millis(50);
+    let mut attempt = 0;
+    loop {
+        match operation() {
+            Err(err) if attempt < max_retries && is_retryable(&err) => {
+                attempt += 1;
+                log::warning!(
+                    "Retrying {} ({}/{}) due to error: {:#}",
+                    description,
+                    attempt,
+                    max_retries,
+                    err
+                );
+                std::thread::sleep(backoff);
+                backoff *= 2;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Returns true iff the primitive only creates a single file or directory tree, and hence can be applied independently of its siblings.
+fn is_independent(p: &FsPrimitive) -> bool {
+    matches!(
+        p,
+        FsPrimitive::Link { .. }
+            | FsPrimitive::CopyFile { .. }
+            | FsPrimitive::CopyDir { .. }
+            | FsPrimitive::HardLink { .. }
+            | FsPrimitive::Rename { .. }
+    )
+}
+
+/// Applies the given independent primitives in parallel and returns the results in the original order.
+fn apply_parallel(
+    primitives: Vec<FsPrimitive>,
+    backup_dir: Option<&PathBuf>,
+) -> Vec<anyhow::Result<FsPrimitive>> {
+    let num_threads = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1);
+    let chunk_size = primitives.len().div_ceil(num_threads).max(1);
+    let mut chunks = vec![];
+    let mut primitives = primitives.into_iter().peekable();
+    while primitives.peek().is_some() {
+        chunks.push(primitives.by_ref().take(chunk_size).collect_vec());
+    }
+    std::thread::scope(|s| {
+        chunks
+            .into_iter()
+            .map(|chunk| {
+                s.spawn(move || {
+                    chunk
+                        .into_iter()
+                        .map(|p| apply_with_retries(p, backup_dir))
+                        .collect_vec()
+                })
+            })
+            .collect_vec()
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("could not join the applying thread"))
+            .collect_vec()
+    })
+}
+
 /// Runs the given list of primitives sequentially while populating the given list of inverse primitives.
+/// Consecutive independent primitives are applied in parallel.
 fn run_sequentially(
+    primitives: Vec<FsPrimitive>,
+    inv_primitives: Option<&mut Vec<FsPrimitive>>,
+    backup_dir: Option<&PathBuf>,
+    info_icon: &'static str,
+    display_mode: DisplayMode,
+) -> anyhow::Result<()> {
+    let total = primitives.len();
+    let res = apply_all(
+        primitives,
+        inv_primitives,
+        backup_dir,
+        info_icon,
+        display_mode,
+    );
+    if display_mode == DisplayMode::Progress && total > 0 {
+        println!();
+    }
+    res
+}
+
+/// Applies every given primitive sequentially, continuing past the failures, and returns all the
+/// errors that occurred. Used for rollbacks, where restoring as much as possible matters most.
+fn run_exhaustively(
+    primitives: Vec<FsPrimitive>,
+    info_icon: &'static str,
+    display_mode: DisplayMode,
+) -> Vec<anyhow::Error> {
+    let total = primitives.len();
+    let mut errors = vec![];
+    for (i, m) in primitives.into_iter().enumerate() {
+        let desc = m.to_string();
+        if display_mode == DisplayMode::PerLine {
+            println!(" {} {}", info_icon, desc);
+        }
+        if let Err(err) = apply_with_retries(m, None) {
+            errors.push(err.context(format!("could not apply {}", desc)));
+        }
+        if display_mode == DisplayMode::Progress {
+            print_progress(info_icon, i + 1, total, &desc);
+        }
+    }
+    if display_mode == DisplayMode::Progress && total > 0 {
+        println!();
+    }
+    errors
+}
+
+fn apply_all(
     primitives: Vec<FsPrimitive>,
     mut inv_primitives: Option<&mut Vec<FsPrimitive>>,
     backup_dir: Option<&PathBuf>,
-    info_icon: Option<&'static str>,
+    info_icon: &'static str,
+    display_mode: DisplayMode,
 ) -> anyhow::Result<()> {
-    for m in primitives.into_iter() {
-        if let Some(info_icon) = info_icon {
-            println!(" {} {}", info_icon, m);
+    let total = primitives.len();
+    let mut done = 0;
+    let mut primitives = primitives.into_iter().peekable();
+    while let Some(m) = primitives.next() {
+        // Only the runs that keep track of the inverses can be rolled back after an interruption.
+        if inv_primitives.is_some() && utils::is_interrupted() {
+            anyhow::bail!("interrupted");
+        }
+        let mut batch = vec![m];
+        if is_independent(&batch[0]) {
+            while batch.len() < MAX_BATCH_SIZE {
+                let Some(next) = primitives.next_if(is_independent) else {
+                    break;
+                };
+                batch.push(next);
+            }
+        }
+        let last_desc = batch.last().map(|m| m.to_string()).unwrap_or_default();
+        if display_mode == DisplayMode::PerLine {
+            batch.iter().for_each(|m| println!(" {} {}", info_icon, m));
         }
-        let m_inv = m.apply(backup_dir)?;
-        if let Some(inv_mods) = &mut inv_primitives {
-            inv_mods.insert(0, m_inv);
+        done += batch.len();
+        let results = if batch.len() > 1 {
+            apply_parallel(batch, backup_dir)
+        } else {
+            batch
+                .into_iter()
+                .map(|m| apply_with_retries(m, backup_dir))
+                .collect_vec()
+        };
+        // Keep track of the inverses of the applied primitives even if some of them have failed.
+        let mut first_err = None;
+        for res in results {
+            match res {
+                Ok(m_inv) => {
+                    if let Some(inv_mods) = &mut inv_primitives {
+                        inv_mods.insert(0, m_inv);
+                    }
+                }
+                Err(err) => {
+                    first_err.get_or_insert(err);
+                }
+            }
+        }
+        if display_mode == DisplayMode::Progress {
+            print_progress(info_icon, done, total, &last_desc);
+        }
+        if let Some(err) = first_err {
+            return Err(err);
         }
     }
     Ok(())
@@ -25,72 +252,144 @@ fn run_sequentially(
 
 impl Transaction {
     /// Interprets the transaction as a list of primitives and applies them sequentially until an error occurs.
-    pub fn run_haphazard(self, verbose: bool) -> anyhow::Result<()> {
-        if verbose {
-            println!("Running filesystem modifications ({})", self.name);
-            println!("Directory: {:?}", self.backup_dir);
-        }
-        if let Err(err) = run_sequentially(
-            self.primitives,
-            None,
-            None,
-            if verbose { Some(".") } else { None },
-        ) {
-            println!(" ✗ Execution failed");
+    pub fn run_haphazard(self, display_mode: DisplayMode) -> anyhow::Result<()> {
+        log::debug!(
+            "Running filesystem modifications ({}, {} primitives)",
+            self.name,
+            self.primitives.len()
+        );
+        log::debug!("Directory: {:?}", self.backup_dir);
+        if let Err(err) = run_sequentially(self.primitives, None, None, ".", display_mode) {
+            log::error!(" ✗ Execution failed");
             Err(err)
         } else {
-            if verbose {
-                println!(" ✓ Execution succeeded");
-            }
+            log::debug!(" ✓ Execution succeeded");
             Ok(())
         }
     }
 
     /// Runs the transaction in an atomic manner. This means if an error occurs, we try to rollback.
-    pub fn run_atomic(self, verbose: bool) -> TxResult {
-        if verbose {
-            println!("Running transaction ({})", self.name);
-        }
+    /// Interrupts are deferred until the next batch of primitives, and then roll back the transaction.
+    pub fn run_atomic(self, display_mode: DisplayMode) -> TxResult {
+        let _interrupt_guard = utils::InterruptGuard::install();
+        log::debug!(
+            "Running transaction ({}, {} primitives)",
+            self.name,
+            self.primitives.len()
+        );
         // Run the transaction sequentially while keeping track of its inverse.
         let mut inv_mods = vec![];
         let run_res = run_sequentially(
             self.primitives,
             Some(&mut inv_mods),
-            Some(&self.backup_dir),
-            if verbose { Some("→") } else { None },
+            // Without a backup directory, the removals are inverted to no-ops.
+            (!self.no_backup).then_some(&self.backup_dir),
+            "→",
+            display_mode,
         )
         // Then try to generate the undo transaction from the inverted primitives.
         .and_then(|_| {
             let mut txb = TxBuilder::empty();
             // Clone is kind of unnecessary, but I want to make the compiler happy.
             inv_mods.clone().into_iter().for_each(|p| txb.push(p));
-            let undo_tx = txb.build(format!("Undo{}", self.name))?;
+            let undo_tx = txb.build_undo(format!("Undo{}", self.name))?;
+            if let Err(err) = Transaction::record_undo(&self.backup_dir, &undo_tx) {
+                log::warning!("Could not record the undo transaction: {:?}", err);
+            }
             Ok(undo_tx)
         });
         match run_res {
             Ok(undo_tx) => {
-                if verbose {
-                    println!(" ✓ Transaction succeeded");
-                }
+                log::debug!(" ✓ Transaction succeeded");
                 TxResult::Success(undo_tx)
             }
             Err(tx_err) => {
-                println!(" ✗ Transaction failed, trying to roll back");
+                log::error!(" ✗ Transaction failed, trying to roll back");
                 // Run the history (inverted) to rollback.
-                if let Err(rb_err) =
-                    run_sequentially(inv_mods, None, None, if verbose { Some("←") } else { None })
-                {
-                    println!(" ✗ Transaction rollback failed");
-                    println!(
+                let rb_errs = run_exhaustively(inv_mods, "←", display_mode);
+                if !rb_errs.is_empty() {
+                    log::error!(
+                        " ✗ Transaction rollback failed for {} primitives",
+                        rb_errs.len()
+                    );
+                    log::error!(
                         " ✗ Backed up files remain at {:?}, good luck =)",
                         self.backup_dir
                     );
-                    TxResult::FatalFailure { tx_err, rb_err }
+                    TxResult::FatalFailure { tx_err, rb_errs }
                 } else {
-                    println!(" ✓ Transaction rollback succeeded");
+                    log::info!(" ✓ Transaction rollback succeeded");
                     TxResult::TxFailure(tx_err)
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::transaction::{Filesystem, MockFilesystem};
+
+    #[test]
+    fn retries_transient_failures() {
+        let fs = MockFilesystem::default()
+            .with_file("/home/user/config")
+            .failing_transiently(2);
+        let entries = retry_transient(&"reading", 2, || fs.read_dir(Path::new("/home/user")));
+        assert_eq!(entries.unwrap(), vec![PathBuf::from("/home/user/config")]);
+        let fs = fs.failing_transiently(2);
+        let entries = retry_transient(&"reading", 1, || fs.read_dir(Path::new("/home/user")));
+        assert!(is_retryable(&entries.unwrap_err()));
+    }
+
+    #[test]
+    fn does_not_retry_conflicts() {
+        let fs = MockFilesystem::default();
+        let mut attempts = 0;
+        let entries = retry_transient(&"reading", 2, || {
+            attempts += 1;
+            fs.read_dir(Path::new("/home/user"))
+        });
+        assert!(entries.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn applies_and_undoes_large_transactions() {
+        let dir = utils::test_dir();
+        let source = dir.join("source");
+        std::fs::write(&source, "contents").unwrap();
+        std::fs::create_dir(dir.join("target")).unwrap();
+        // The directories precede their leaves, so that they are applied in separate batches.
+        let mut txb = TxBuilder::empty();
+        let mut targets = vec![];
+        for subdir in 0..50 {
+            let subdir = dir.join("target").join(format!("dir{}", subdir));
+            txb.create_dir(&subdir);
+            for file in 0..99 {
+                let target = subdir.join(format!("file{}", file));
+                if file % 2 == 0 {
+                    txb.link(&source, &target);
+                } else {
+                    txb.copy_file(&source, &target);
+                }
+                targets.push(target);
+            }
+        }
+        let tx = txb.build("Large").unwrap();
+        assert_eq!(tx.num_primitives(), 5000);
+        let undo_tx = tx.run_atomic(DisplayMode::Silent).into_tx_result().unwrap();
+        assert!(targets.iter().all(|target| target.is_file()));
+        undo_tx
+            .run_atomic(DisplayMode::Silent)
+            .into_tx_result()
+            .unwrap();
+        assert!(dir
+            .join("target")
+            .read_dir()
+            .is_ok_and(|mut entries| entries.next().is_none()));
+    }
+}
@@ -1,16 +1,88 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
 use anyhow::Context;
 use itertools::Itertools;
 
+use crate::{globals, log};
+
 use super::{primitives::FsPrimitive, Transaction};
 
+/// Warns about, or in the strict mode refuses, transactions with more primitives than the limit.
+/// Reports the directories that contribute the most primitives.
+fn check_primitive_limit(name: &str, primitives: &[FsPrimitive]) -> anyhow::Result<()> {
+    let limit = globals::primitive_limit();
+    if primitives.len() <= limit.max {
+        return Ok(());
+    }
+    // Group the targets by the directories right under their common ancestor, ignoring the directories
+    // themselves as their ancestors are shared by all the targets.
+    let file_targets = primitives
+        .iter()
+        .filter(|p| !matches!(p, FsPrimitive::CreateDir(..) | FsPrimitive::RemoveDir(_)))
+        .flat_map(|p| p.target())
+        .collect_vec();
+    let targets = if file_targets.is_empty() {
+        primitives.iter().flat_map(|p| p.target()).collect_vec()
+    } else {
+        file_targets
+    };
+    let common_ancestor = targets
+        .iter()
+        .map(|target| target.as_path())
+        .reduce(|common, target| {
+            common
+                .ancestors()
+                .find(|ancestor| target.starts_with(ancestor))
+                .unwrap_or(Path::new(""))
+        })
+        .unwrap_or(Path::new(""));
+    let report = targets
+        .iter()
+        .map(|target| {
+            let top_level = target
+                .strip_prefix(common_ancestor)
+                .ok()
+                .and_then(|stripped| stripped.components().next());
+            match top_level {
+                Some(component) => common_ancestor.join(component),
+                None => common_ancestor.to_path_buf(),
+            }
+        })
+        .counts()
+        .into_iter()
+        .sorted_by_key(|(_, count)| std::cmp::Reverse(*count))
+        .take(5)
+        .map(|(dir, count)| format!(" - {:?}: {} entries", dir, count))
+        .join("\n");
+    let message = format!(
+        "the transaction {:?} has {} primitives, more than the limit of {}:\n{}",
+        name,
+        primitives.len(),
+        limit.max,
+        report
+    );
+    if limit.strict {
+        anyhow::bail!(message);
+    }
+    log::warning!("{}", message);
+    Ok(())
+}
+
 /// A type that can be used to build transactions.
 pub struct TxBuilder {
     files_to_create: HashMap<PathBuf, FsPrimitive>,
     files_to_remove: HashMap<PathBuf, FsPrimitive>,
     dirs_to_create: HashMap<PathBuf, FsPrimitive>,
     dirs_to_remove: HashMap<PathBuf, FsPrimitive>,
+    /// Applied after the paths are created and before they are removed.
+    modes_to_set: HashMap<PathBuf, FsPrimitive>,
+    /// The pairs of primitives that create and remove the same path.
+    contradictions: Vec<(FsPrimitive, FsPrimitive)>,
+    /// Maps the directory prefixes to the permission bits of the directories created under them.
+    dir_modes: HashMap<PathBuf, u32>,
 }
 
 impl TxBuilder {
@@ -21,14 +93,37 @@ impl TxBuilder {
             files_to_remove: Default::default(),
             dirs_to_create: Default::default(),
             dirs_to_remove: Default::default(),
+            modes_to_set: Default::default(),
+            contradictions: Default::default(),
+            dir_modes: Default::default(),
         }
     }
 
+    /// Sets the permission bits of the directories that are created under the given prefixes.
+    /// If multiple prefixes contain a directory, the longest one is used.
+    pub fn set_dir_modes(&mut self, dir_modes: HashMap<PathBuf, u32>) {
+        self.dir_modes = dir_modes;
+    }
+
     /// Returns true iff the transaction generated at the current state of the builder would create the given directory.
     pub fn will_create_dir(&self, p: &PathBuf) -> bool {
         self.dirs_to_create.contains_key(p)
     }
 
+    /// Returns the original of the link that the transaction generated at the current state of the builder would
+    /// create at the given path, if any.
+    pub(super) fn pending_link(&self, p: &PathBuf) -> Option<&PathBuf> {
+        match self.files_to_create.get(p) {
+            Some(FsPrimitive::Link { original, .. }) => Some(original),
+            _ => None,
+        }
+    }
+
+    /// Returns the directories that the transaction generated at the current state of the builder would create.
+    pub fn created_dirs(&self) -> impl Iterator<Item = &PathBuf> {
+        self.dirs_to_create.keys()
+    }
+
     /// Returns true iff the transaction generated at the current state of the builder would remove the given directory.
     pub fn _will_remove_dir(&self, p: &PathBuf) -> bool {
         self.dirs_to_remove.contains_key(p)
@@ -44,32 +139,42 @@ impl TxBuilder {
         self.files_to_remove.contains_key(p)
     }
 
+    /// Appends the given primitive. Creating a path that is removed by a previous primitive, or vice versa,
+    /// is recorded as a contradiction that fails the build.
     pub(super) fn push(&mut self, p: FsPrimitive) {
-        match &p {
-            FsPrimitive::Link {
-                original: _,
-                target,
-            } => {
-                self.files_to_remove.remove(target);
+        let contradicting = match &p {
+            FsPrimitive::Link { target, .. }
+            | FsPrimitive::CopyFile { target, .. }
+            | FsPrimitive::CopyDir { target, .. }
+            | FsPrimitive::HardLink { target, .. }
+            | FsPrimitive::Rename { to: target, .. } => {
+                let removed = self.files_to_remove.remove(target);
                 self.files_to_create.insert(target.clone(), p.clone());
+                removed
             }
-            FsPrimitive::CopyFile { source: _, target } => {
-                self.files_to_remove.remove(target);
-                self.files_to_create.insert(target.clone(), p.clone());
-            }
-            FsPrimitive::RemoveFile(target) => {
-                self.files_to_create.remove(target);
+            FsPrimitive::RemoveFile(target) | FsPrimitive::RemoveDirAll(target) => {
+                let created = self.files_to_create.remove(target);
                 self.files_to_remove.insert(target.clone(), p.clone());
+                created
             }
             FsPrimitive::RemoveDir(target) => {
-                self.dirs_to_create.remove(target);
+                let created = self.dirs_to_create.remove(target);
                 self.dirs_to_remove.insert(target.clone(), p.clone());
+                created
             }
-            FsPrimitive::CreateDir(target) => {
-                self.dirs_to_remove.remove(target);
+            FsPrimitive::CreateDir(target, _) => {
+                let removed = self.dirs_to_remove.remove(target);
                 self.dirs_to_create.insert(target.clone(), p.clone());
+                removed
+            }
+            FsPrimitive::SetMode { target, .. } => {
+                self.modes_to_set.insert(target.clone(), p.clone());
+                None
             }
-            FsPrimitive::Nop => {}
+            FsPrimitive::Nop => None,
+        };
+        if let Some(contradicting) = contradicting {
+            self.contradictions.push((contradicting, p));
         }
     }
 
@@ -99,14 +204,60 @@ impl TxBuilder {
         });
     }
 
+    /// Appends an instruction to hard link the regular file that exists at `original` to `target`,
+    /// falling back to copying across filesystems.
+    /// Note that `target` must not exist, but it's parent directories must exist.
+    pub fn hard_link<P1, P2>(&mut self, original: P1, target: P2)
+    where
+        P1: Into<PathBuf>,
+        P2: Into<PathBuf>,
+    {
+        self.push(FsPrimitive::HardLink {
+            original: original.into(),
+            target: target.into(),
+        });
+    }
+
+    /// Appends an instruction to recursively copy the directory that exists at `source` to `target`,
+    /// leaving out the files and directories with the given names at any depth.
+    /// Note that `target` must not exist, but it's parent directories must exist.
+    pub fn copy_dir<P1, P2>(&mut self, source: P1, target: P2, ignore_filenames: &[String])
+    where
+        P1: Into<PathBuf>,
+        P2: Into<PathBuf>,
+    {
+        self.push(FsPrimitive::CopyDir {
+            source: source.into(),
+            target: target.into(),
+            ignore: ignore_filenames.to_vec(),
+        });
+    }
+
+    /// Appends an instruction to set the permission bits of the file at the given path, which must
+    /// exist by the time the instruction is applied.
+    pub fn set_mode<P: Into<PathBuf>>(&mut self, target: P, mode: u32) {
+        self.push(FsPrimitive::SetMode {
+            target: target.into(),
+            mode,
+        });
+    }
+
     /// Appends an instruction to remove the file/symlink at the given path.
     pub fn remove_file<P: Into<PathBuf>>(&mut self, target: P) {
         self.push(FsPrimitive::RemoveFile(target.into()));
     }
 
     /// Appends an instruction to create an empty directory at the given free path.
+    /// The directory is created with the mode of its prefix, if there is one.
     pub fn create_dir<P: Into<PathBuf>>(&mut self, target: P) {
-        self.push(FsPrimitive::CreateDir(target.into()));
+        let target = target.into();
+        let mode = self
+            .dir_modes
+            .iter()
+            .filter(|(prefix, _)| target.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.components().count())
+            .map(|(_, mode)| *mode);
+        self.push(FsPrimitive::CreateDir(target, mode));
     }
 
     /// Appends an instruction to remove the free directory at the given path.
@@ -120,11 +271,34 @@ impl TxBuilder {
             + self.dirs_to_remove.len()
             + self.files_to_create.len()
             + self.files_to_remove.len()
+            + self.modes_to_set.len()
     }
 
     /// Builds an actual transaction that can be executed.
+    /// Fails if the builder was given contradictory instructions.
     pub fn build<S: Into<String>>(self, name: S) -> anyhow::Result<Transaction> {
-        let name = name.into();
+        self.build_checked(name.into(), true)
+    }
+
+    /// Builds the undo transaction of a transaction that was already run, which is not subject to the
+    /// limit on the number of primitives.
+    pub(super) fn build_undo<S: Into<String>>(self, name: S) -> anyhow::Result<Transaction> {
+        self.build_checked(name.into(), false)
+    }
+
+    fn build_checked(self, name: String, check_limit: bool) -> anyhow::Result<Transaction> {
+        if !self.contradictions.is_empty() {
+            let report = self
+                .contradictions
+                .iter()
+                .map(|(first, second)| format!(" - `{}` contradicts `{}`", second, first))
+                .join("\n");
+            anyhow::bail!(
+                "contradictory instructions for the transaction {:?}:\n{}",
+                name,
+                report
+            );
+        }
         let mut primitives = Vec::with_capacity(self.len());
         primitives.extend(
             self.dirs_to_create
@@ -138,6 +312,12 @@ impl TxBuilder {
                 .sorted_by_key(|(dir, _)| dir.components().count())
                 .map(|(_, prm)| prm),
         );
+        primitives.extend(
+            self.modes_to_set
+                .into_iter()
+                .sorted_by(|(a, _), (b, _)| a.cmp(b))
+                .map(|(_, prm)| prm),
+        );
         primitives.extend(
             self.files_to_remove
                 .into_iter()
@@ -152,6 +332,9 @@ impl TxBuilder {
                 .rev()
                 .map(|(_, prm)| prm),
         );
+        if check_limit {
+            check_primitive_limit(&name, &primitives)?;
+        }
         Transaction::generate(name.clone(), primitives)
             .context(format!("could not build the transaction {:?}", name))
     }
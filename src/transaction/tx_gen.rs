@@ -1,38 +1,178 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use anyhow::Context;
 use itertools::Itertools;
-use walkdir::WalkDir;
 
 use crate::utils;
 
 use super::TxBuilder;
 
+/// Provides the state of the filesystem that the transactions are generated against.
+pub trait Filesystem {
+    /// Returns true iff something exists at the path, without following symlinks.
+    fn exists(&self, path: &Path) -> bool;
+    /// Returns true iff the path is a symlink.
+    fn is_symlink(&self, path: &Path) -> bool;
+    /// Returns true iff the path is a regular file, following symlinks.
+    fn is_file(&self, path: &Path) -> bool;
+    /// Returns the paths of the direct contents of the directory at the path.
+    fn read_dir(&self, path: &Path) -> anyhow::Result<Vec<PathBuf>>;
+
+    /// Returns the path along with all the paths under it, without following symlinks.
+    /// Returns nothing if the path does not exist.
+    fn walk(&self, path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+        let mut paths = vec![];
+        let mut frontier = vec![path.to_path_buf()];
+        while let Some(curr_path) = frontier.pop() {
+            if !self.exists(&curr_path) {
+                continue;
+            }
+            if !self.is_symlink(&curr_path) && !self.is_file(&curr_path) {
+                frontier.extend(self.read_dir(&curr_path)?);
+            }
+            paths.push(curr_path);
+        }
+        Ok(paths)
+    }
+}
+
 /// Captures the current state of the filesystem.
 pub struct ActualFilesystem;
 
+impl Filesystem for ActualFilesystem {
+    fn exists(&self, path: &Path) -> bool {
+        path.symlink_metadata().is_ok()
+    }
+
+    fn is_symlink(&self, path: &Path) -> bool {
+        path.is_symlink()
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn read_dir(&self, path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+        Ok(path
+            .read_dir()
+            .context(format!("could not read the directory contents {:?}", path))?
+            .flatten()
+            .map(|entry| entry.path())
+            .collect_vec())
+    }
+}
+
+/// An in-memory filesystem to generate the transactions against in the tests.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MockFilesystem {
+    entries: std::collections::HashMap<PathBuf, MockEntry>,
+    /// Number of the upcoming directory reads that fail with a transient error.
+    transient_failures: std::cell::Cell<u32>,
+}
+
+#[cfg(test)]
+#[derive(Debug)]
+enum MockEntry {
+    File,
+    Dir,
+    Symlink(PathBuf),
+}
+
+#[cfg(test)]
+impl MockFilesystem {
+    /// Adds the entry at the given path along with its missing ancestor directories.
+    fn with_entry<P: Into<PathBuf>>(mut self, path: P, entry: MockEntry) -> Self {
+        let path = path.into();
+        for ancestor in path.ancestors().skip(1) {
+            self.entries
+                .entry(ancestor.to_path_buf())
+                .or_insert(MockEntry::Dir);
+        }
+        self.entries.insert(path, entry);
+        self
+    }
+
+    pub fn with_file<P: Into<PathBuf>>(self, path: P) -> Self {
+        self.with_entry(path, MockEntry::File)
+    }
+
+    pub fn with_dir<P: Into<PathBuf>>(self, path: P) -> Self {
+        self.with_entry(path, MockEntry::Dir)
+    }
+
+    pub fn with_symlink<P1: Into<PathBuf>, P2: Into<PathBuf>>(
+        self,
+        path: P1,
+        original: P2,
+    ) -> Self {
+        self.with_entry(path, MockEntry::Symlink(original.into()))
+    }
+
+    /// Makes the next `failures` directory reads fail as if interrupted.
+    pub fn failing_transiently(self, failures: u32) -> Self {
+        self.transient_failures.set(failures);
+        self
+    }
+}
+
+#[cfg(test)]
+impl Filesystem for MockFilesystem {
+    fn exists(&self, path: &Path) -> bool {
+        self.entries.contains_key(path)
+    }
+
+    fn is_symlink(&self, path: &Path) -> bool {
+        matches!(self.entries.get(path), Some(MockEntry::Symlink(_)))
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        match self.entries.get(path) {
+            Some(MockEntry::File) => true,
+            Some(MockEntry::Symlink(original)) => self.is_file(original),
+            _ => false,
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+        let failures = self.transient_failures.get();
+        if failures > 0 {
+            self.transient_failures.set(failures - 1);
+            return Err(std::io::Error::from(std::io::ErrorKind::Interrupted))
+                .context(format!("could not read the directory contents {:?}", path));
+        }
+        if !matches!(self.entries.get(path), Some(MockEntry::Dir)) {
+            anyhow::bail!("could not read the directory contents {:?}", path);
+        }
+        Ok(self
+            .entries
+            .keys()
+            .filter(|entry| entry.parent() == Some(path))
+            .cloned()
+            .sorted()
+            .collect_vec())
+    }
+}
+
 impl TxBuilder {
     /// Instruct to remove the given directory with all of its contents.
-    pub fn remove_dir_all(
+    pub fn remove_dir_all<F: Filesystem>(
         &mut self,
         target: &PathBuf,
-        _fs: &ActualFilesystem,
+        fs: &F,
     ) -> anyhow::Result<()> {
-        if target.is_symlink() || target.is_file() {
+        if fs.is_symlink(target) || fs.is_file(target) {
             anyhow::bail!("target {:?} is not a directory", target)
         }
         // Construct the transaction.
-        let target_files = WalkDir::new(target)
-            .follow_root_links(false)
-            .follow_links(false)
+        let target_files = fs
+            .walk(target)?
             .into_iter()
-            .flatten()
-            .map(|p| p.path().to_path_buf())
             // Start removing from the innermost paths (stable sort is important)
             .sorted_by_key(|p| p.components().count())
             .rev();
         for inner_target in target_files {
-            let is_file = inner_target.is_symlink() || inner_target.is_file();
+            let is_file = fs.is_symlink(&inner_target) || fs.is_file(&inner_target);
             if is_file {
                 self.remove_file(inner_target);
             } else {
@@ -43,8 +183,8 @@ impl TxBuilder {
     }
 
     /// Instruct to remove anything in the given target. If `target` is a symlink, only removes the symlink.
-    pub fn remove_any(&mut self, target: &PathBuf, fs: &ActualFilesystem) -> anyhow::Result<()> {
-        if target.is_symlink() || target.is_file() {
+    pub fn remove_any<F: Filesystem>(&mut self, target: &PathBuf, fs: &F) -> anyhow::Result<()> {
+        if fs.is_symlink(target) || fs.is_file(target) {
             self.remove_file(target);
         } else {
             self.remove_dir_all(target, fs)?;
@@ -52,12 +192,13 @@ impl TxBuilder {
         Ok(())
     }
 
-    /// Instruct to create the given symlinks.
-    pub fn create_links<P: Into<PathBuf>>(
+    /// Instruct to create the given symlinks. The identical links are only created once.
+    /// Fails if two links with different sources have the same target.
+    pub fn create_links<P: Into<PathBuf>, F: Filesystem>(
         &mut self,
         root: P,
         links: Vec<utils::ResolvedLink>,
-        fs: &ActualFilesystem,
+        fs: &F,
     ) -> anyhow::Result<()> {
         let root: PathBuf = root.into();
         for link in links.into_iter() {
@@ -69,6 +210,16 @@ impl TxBuilder {
             };
             curr_virt_target.push(relativized_target);
             curr_virt_target = utils::expand_path(&curr_virt_target)?;
+            match self.pending_link(&curr_virt_target) {
+                Some(original) if *original == link.abs_source => continue,
+                Some(original) => anyhow::bail!(
+                    "conflicting links at {:?} to both {:?} and {:?}",
+                    link.abs_target,
+                    original,
+                    link.abs_source
+                ),
+                None => {}
+            }
             // Create the virtual directory if it does not exist.
             let curr_virt_target_parent = curr_virt_target.parent().context(format!(
                 "could not get the parent of {:?}",
@@ -81,22 +232,23 @@ impl TxBuilder {
     }
 
     /// Instruct to ensure the existence of the given directory.
-    pub fn ensure_dirs<P: Into<PathBuf>>(
+    pub fn ensure_dirs<P: Into<PathBuf>, F: Filesystem>(
         &mut self,
         target: P,
-        _fs: &ActualFilesystem,
+        fs: &F,
     ) -> anyhow::Result<()> {
         let path = target.into();
-        // Get the ancestor paths.
+        // Get the ancestor paths, excluding the empty ancestor of relative paths.
         let ancestors = path
             .ancestors()
+            .filter(|ancestor| !ancestor.as_os_str().is_empty())
             .map(|ancestor| ancestor.to_path_buf())
             .collect_vec();
         // For each parent subdirectory that does not exist, add a new create dir primitive.
         ancestors
             .into_iter()
             .rev()
-            .filter(|subdir| !subdir.symlink_metadata().is_ok())
+            .filter(|subdir| !fs.exists(subdir))
             .filter(|subdir| !self.will_create_dir(subdir))
             .collect_vec()
             .into_iter()
@@ -104,3 +256,52 @@ impl TxBuilder {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::FsPrimitive;
+
+    #[test]
+    fn removes_contents_before_directories() {
+        let _dir = utils::test_dir();
+        let fs = MockFilesystem::default()
+            .with_file("/home/user/app/config")
+            .with_symlink("/home/user/app/link", "/home/user/app/config")
+            .with_dir("/home/user/app/empty");
+        let mut txb = TxBuilder::empty();
+        txb.remove_any(&PathBuf::from("/home/user/app"), &fs)
+            .unwrap();
+        let tx = txb.build("Test").unwrap();
+        let position = |primitive: FsPrimitive| {
+            tx.primitives
+                .iter()
+                .position(|p| *p == primitive)
+                .unwrap_or_else(|| panic!("missing {}", primitive))
+        };
+        let dir = position(FsPrimitive::RemoveDir("/home/user/app".into()));
+        let empty = position(FsPrimitive::RemoveDir("/home/user/app/empty".into()));
+        let config = position(FsPrimitive::RemoveFile("/home/user/app/config".into()));
+        let link = position(FsPrimitive::RemoveFile("/home/user/app/link".into()));
+        assert_eq!(tx.primitives.len(), 4);
+        assert!(config < empty && link < empty && empty < dir);
+    }
+
+    #[test]
+    fn ensures_only_the_missing_dirs() {
+        let _dir = utils::test_dir();
+        let fs = MockFilesystem::default().with_dir("/home/user/.config");
+        let mut txb = TxBuilder::empty();
+        txb.ensure_dirs("/home/user/.config/app/themes", &fs)
+            .unwrap();
+        txb.ensure_dirs("/home/user/.config/app", &fs).unwrap();
+        let tx = txb.build("Test").unwrap();
+        assert_eq!(
+            tx.primitives,
+            vec![
+                FsPrimitive::CreateDir("/home/user/.config/app".into(), None),
+                FsPrimitive::CreateDir("/home/user/.config/app/themes".into(), None),
+            ]
+        );
+    }
+}
@@ -0,0 +1,68 @@
+use std::io::Write;
+
+use anyhow::Context;
+
+use crate::globals;
+
+use super::Transaction;
+
+/// An entry of the transaction log.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct TxLogEntry {
+    pub id: String,
+    pub name: String,
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub num_primitives: usize,
+    pub success: bool,
+    /// The error of the transaction, if it failed.
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl TxLogEntry {
+    /// Creates a log entry for the given transaction, timestamped now.
+    pub fn new(tx: &Transaction, success: bool) -> Self {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        Self {
+            id: tx.id.clone(),
+            name: tx.name.clone(),
+            timestamp,
+            num_primitives: tx.primitives.len(),
+            success,
+            error: None,
+        }
+    }
+
+    /// Reads all the entries of the transaction log, skipping the malformed ones.
+    pub fn read_all() -> anyhow::Result<Vec<Self>> {
+        let log_path = globals::transactions_dir().join("log.jsonl");
+        if !log_path.exists() {
+            return Ok(vec![]);
+        }
+        let contents = std::fs::read_to_string(&log_path)
+            .context(format!("could not read the transaction log {:?}", log_path))?;
+        Ok(contents
+            .lines()
+            .flat_map(|line| serde_json::from_str(line).ok())
+            .collect())
+    }
+
+    /// Appends this entry to the transaction log.
+    pub fn append(&self) -> anyhow::Result<()> {
+        let log_path = globals::transactions_dir().join("log.jsonl");
+        let mut log_file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)
+            .context(format!("could not open the transaction log {:?}", log_path))?;
+        let line = serde_json::to_string(self).context("could not serialize the log entry")?;
+        writeln!(log_file, "{}", line).context(format!(
+            "could not write to the transaction log {:?}",
+            log_path
+        ))
+    }
+}
@@ -1,56 +1,218 @@
+use std::path::PathBuf;
+
 use anyhow::Context;
 
-use super::Transaction;
+use crate::{failure::Failure, log, utils};
+
+use super::{DisplayMode, ScriptPaths, Transaction, TxLogEntry, TxSummary};
 
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 pub struct TxProcessor {
     name: String,
-    verbose: bool,
+    display_mode: DisplayMode,
     processed: Vec<Transaction>,
+    /// The number of processed transactions that are kept when rolling back.
+    savepoint: usize,
+    tx_dirs: Vec<PathBuf>,
+    /// If set, the transactions are collected into this shell script instead of being run.
+    script: Option<String>,
+    /// The paths that the script creates and removes, so that they are not removed twice.
+    script_paths: ScriptPaths,
+    /// If set, the effects of the transactions are displayed instead of being run.
+    dry_run: bool,
+    /// If set, the transactions are run without backing up the removed paths.
+    no_backup: bool,
+    /// The effects of the transactions that were successfully run.
+    summary: TxSummary,
 }
 
 impl TxProcessor {
-    pub fn new<S: Into<String>>(name: S, verbose: bool) -> Self {
+    pub fn new<S: Into<String>>(name: S, display_mode: DisplayMode) -> Self {
         Self {
-            verbose,
+            display_mode,
             name: name.into(),
             processed: Default::default(),
+            savepoint: 0,
+            tx_dirs: Default::default(),
+            script: None,
+            script_paths: Default::default(),
+            dry_run: false,
+            no_backup: false,
+            summary: Default::default(),
+        }
+    }
+
+    /// Makes the processor collect the transactions into a shell script instead of running them.
+    pub fn emitting_script(self) -> Self {
+        Self {
+            script: Some("#!/bin/sh\nset -e\n".to_string()),
+            ..self
+        }
+    }
+
+    /// Makes the processor display the effects of the transactions instead of running them.
+    pub fn dry_running(self) -> Self {
+        Self {
+            dry_run: true,
+            ..self
+        }
+    }
+
+    /// Makes the processor run the transactions without backing up the removed paths, so that the
+    /// removals are not restored on rollback.
+    pub fn without_backups(self) -> Self {
+        Self {
+            no_backup: true,
+            ..self
         }
     }
 
+    /// Returns the shell script of the collected transactions, if the processor is emitting a script.
+    pub fn script(&self) -> Option<&str> {
+        self.script.as_deref()
+    }
+
+    /// Returns true iff the transactions are never run, i.e., the processor is emitting a script or
+    /// doing a dry run.
+    pub fn is_simulating(&self) -> bool {
+        self.script.is_some() || self.dry_run
+    }
+
+    /// Returns the effects of the transactions that were successfully run by this processor, including
+    /// the ones that were rolled back afterwards.
+    pub fn summary(&self) -> TxSummary {
+        self.summary
+    }
+
+    /// Returns the directories of the transactions (and their undo transactions) that were successfully run by this processor.
+    pub fn tx_dirs(&self) -> &[PathBuf] {
+        &self.tx_dirs
+    }
+
     /// Runs the given transaction such that the failure of it won't affect the overall progress.
     pub fn run_optional(&mut self, tx: Transaction) -> anyhow::Result<()> {
-        let tx_result = tx.run_atomic(self.verbose);
+        let tx = if self.no_backup {
+            tx.without_backups()
+        } else {
+            tx
+        };
+        let summary = tx.summary();
+        if self.is_simulating() {
+            self.summary += summary;
+            if let Some(script) = &mut self.script {
+                script.push_str(&tx.to_shell_script(&mut self.script_paths));
+            } else {
+                log::result!("{} ({} primitives)", tx.name(), tx.num_primitives());
+                for line in tx.dry_run() {
+                    log::result!(" - {}", line);
+                }
+            }
+            // The transaction will never be run, so its directory is not needed.
+            return std::fs::remove_dir_all(tx.backup_dir()).context(format!(
+                "could not remove the transaction directory {:?}",
+                tx.backup_dir()
+            ));
+        }
+        if utils::is_interrupted() {
+            std::fs::remove_dir_all(tx.backup_dir()).context(format!(
+                "could not remove the transaction directory {:?}",
+                tx.backup_dir()
+            ))?;
+            return Err(anyhow::anyhow!(
+                "interrupted before the transaction {:?}",
+                tx.name()
+            ))
+            .context(Failure::RolledBack);
+        }
+        let tx_dir = tx.backup_dir().clone();
+        let mut log_entry = TxLogEntry::new(&tx, false);
+        let tx_result = tx.run_atomic(self.display_mode);
+        log_entry.success = tx_result.is_success();
+        log_entry.error = tx_result.tx_error().map(|err| format!("{:#}", err));
+        if let Err(err) = log_entry.append() {
+            log::warning!("Could not log the transaction: {:?}", err);
+        }
         if !tx_result.is_success() {
             tx_result.display_report();
         }
-        if tx_result.is_fatal_failure() {
-            panic!("Fatal failure: Filesystem could not be restored.");
-        }
-        let undo_tx = tx_result.as_tx_result()?;
+        let undo_tx = tx_result.into_tx_result()?;
+        self.summary += summary;
+        self.tx_dirs.push(tx_dir);
+        self.tx_dirs.push(undo_tx.backup_dir().clone());
         self.processed.push(undo_tx);
         Ok(())
     }
 
-    /// Runs the given transaction such that the failure of it will cause all the previous transactions executed by this processor to be reversed.
+    /// Marks the transactions processed so far as committed, so that a later failure only reverses the
+    /// transactions that are run after this point.
+    pub fn savepoint(&mut self) {
+        self.savepoint = self.processed.len();
+    }
+
+    /// Runs the given transaction such that the failure of it will cause all the previous transactions
+    /// executed by this processor since the last savepoint to be reversed.
     pub fn run_required(&mut self, tx: Transaction) -> anyhow::Result<()> {
         let run_res = self.run_optional(tx);
         if let Err(err) = run_res {
-            println!("Rolling {} back due to error", self.name);
-            self.rollback()?;
+            log::warning!("Rolling {} back due to error", self.name);
+            if let Err(rb_err) = self.rollback() {
+                return Err(
+                    rb_err.context(format!("could not roll back after the error: {:#}", err))
+                );
+            }
             Err(err)
         } else {
             Ok(())
         }
     }
 
+    /// Reverses the processed transactions since the last savepoint. The remaining transactions are still
+    /// reversed if one of them fails.
     fn rollback(&mut self) -> anyhow::Result<()> {
-        for prev_tx in self.processed.drain(..).rev() {
-            prev_tx
-                .run_haphazard(self.verbose)
-                .context("could not undo the previous transaction")
-                .expect("Fatal failure! Filesystem could not be restored.");
+        // The rollback is never interrupted.
+        let _interrupt_guard = utils::InterruptGuard::install();
+        let mut undo_errs = vec![];
+        for prev_tx in self.processed.drain(self.savepoint..).rev() {
+            let name = prev_tx.name().to_string();
+            if let Err(err) = prev_tx.run_haphazard(self.display_mode) {
+                undo_errs.push(format!("{:?}: {:#}", name, err));
+            }
+        }
+        if !undo_errs.is_empty() {
+            return Err(anyhow::anyhow!(
+                "could not undo the previous transactions:\n{}",
+                undo_errs.join("\n")
+            ))
+            .context(Failure::Fatal);
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::TxBuilder;
+
+    /// Builds a transaction that creates the given directory.
+    fn create_dir(path: &std::path::Path) -> Transaction {
+        let mut txb = TxBuilder::empty();
+        txb.create_dir(path);
+        txb.build("CreateDir").unwrap()
+    }
+
+    #[test]
+    fn rolls_back_to_the_savepoint() {
+        let dir = utils::test_dir();
+        let mut tx_proc = TxProcessor::new("test", DisplayMode::Silent);
+        tx_proc.run_required(create_dir(&dir.join("kept"))).unwrap();
+        tx_proc.savepoint();
+        tx_proc
+            .run_required(create_dir(&dir.join("reverted")))
+            .unwrap();
+        // Creating an existing directory fails, which rolls back to the savepoint.
+        assert!(tx_proc.run_required(create_dir(&dir.join("kept"))).is_err());
+        assert!(dir.join("kept").is_dir());
+        assert!(!dir.join("reverted").exists());
+    }
+}
@@ -1,5 +1,7 @@
 use anyhow::Context;
 
+use crate::{failure::Failure, log};
+
 use super::Transaction;
 
 #[derive(Debug)]
@@ -9,9 +11,10 @@ pub enum TxResult {
     /// Returns a transaction result that denotes a failure during transaction execution with successful rollback.
     TxFailure(anyhow::Error),
     /// Returns a transaction result that denotes a failure during transaction execution with failed rollback.
+    /// Contains the errors of every inverse primitive that could not be applied.
     FatalFailure {
         tx_err: anyhow::Error,
-        rb_err: anyhow::Error,
+        rb_errs: Vec<anyhow::Error>,
     },
 }
 
@@ -21,42 +24,58 @@ impl TxResult {
         matches!(self, &TxResult::Success(_))
     }
 
-    /// Returns `true` if the result denotes a failed transaction and failed rollback.
-    pub fn is_fatal_failure(&self) -> bool {
-        matches!(
-            self,
-            &TxResult::FatalFailure {
-                tx_err: _,
-                rb_err: _
-            }
-        )
+    /// Consumes self and returns the included transaction result. The rollback errors are included in the error.
+    /// The error is categorized by whether the rollback succeeded.
+    pub fn into_tx_result(self) -> anyhow::Result<Transaction> {
+        match self {
+            TxResult::Success(undo_tx) => Ok(undo_tx),
+            TxResult::TxFailure(tx_err) => Err(tx_err)
+                .context("transaction failed")
+                .context(Failure::RolledBack),
+            TxResult::FatalFailure { tx_err, rb_errs } => Err(tx_err)
+                .context(format!(
+                    "transaction failed and could not be rolled back:\n{}",
+                    rb_errs
+                        .iter()
+                        .map(|rb_err| format!("{:#}", rb_err))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                ))
+                .context(Failure::Fatal),
+        }
     }
 
-    /// Consumes self and returns the included transaction result, discarding the rollback result.
-    pub fn as_tx_result(self) -> anyhow::Result<Transaction> {
+    /// Returns the error of the transaction, if it failed.
+    pub fn tx_error(&self) -> Option<&anyhow::Error> {
         match self {
-            TxResult::Success(undo_tx) => Ok(undo_tx),
-            TxResult::TxFailure(tx_err) => Err(tx_err).context("transaction failed"),
-            TxResult::FatalFailure { tx_err, .. } => Err(tx_err).context("transaction failed"),
+            TxResult::Success(_) => None,
+            TxResult::TxFailure(tx_err) => Some(tx_err),
+            TxResult::FatalFailure { tx_err, .. } => Some(tx_err),
         }
     }
 
-    /// Prints a transaction report on the standard output.
-    pub fn display_report(&self) {
+    /// Returns the transaction report, which is empty for a successful transaction.
+    pub fn as_report_string(&self) -> String {
         match self {
             TxResult::TxFailure(tx_err) => {
-                println!("-------");
-                println!("Transaction error: {:?}", tx_err);
-                println!("-------");
+                format!("-------\nTransaction error: {:?}\n-------\n", tx_err)
             }
-            TxResult::FatalFailure { tx_err, rb_err } => {
-                println!("-------");
-                println!("Transaction error: {:?}", tx_err);
-                println!("-------");
-                println!("Rollback error: {:?}", rb_err);
-                println!("-------");
+            TxResult::FatalFailure { tx_err, rb_errs } => {
+                let mut report = format!("-------\nTransaction error: {:?}\n-------\n", tx_err);
+                for rb_err in rb_errs {
+                    report.push_str(&format!("Rollback error: {:?}\n-------\n", rb_err));
+                }
+                report
             }
-            TxResult::Success(_) => {}
-        };
+            TxResult::Success(_) => String::new(),
+        }
+    }
+
+    /// Displays the transaction report as an error, if the transaction failed.
+    pub fn display_report(&self) {
+        let report = self.as_report_string();
+        if !report.is_empty() {
+            log::error!("{}", report.trim_end());
+        }
     }
 }
@@ -0,0 +1,51 @@
+use super::{FsPrimitive, Transaction};
+
+/// Counts the primitives of the transactions by their effects, for the reports.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TxSummary {
+    /// The symlinks and the hard links.
+    pub links: usize,
+    /// The copied files and directories.
+    pub copies: usize,
+    pub created_dirs: usize,
+    /// The removed files and directories.
+    pub removed: usize,
+}
+
+impl std::ops::AddAssign for TxSummary {
+    fn add_assign(&mut self, other: Self) {
+        self.links += other.links;
+        self.copies += other.copies;
+        self.created_dirs += other.created_dirs;
+        self.removed += other.removed;
+    }
+}
+
+impl std::fmt::Display for TxSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} links, {} copies, {} created directories, {} removed targets",
+            self.links, self.copies, self.created_dirs, self.removed
+        )
+    }
+}
+
+impl Transaction {
+    /// Returns the counts of the primitives of this transaction by their effects.
+    pub fn summary(&self) -> TxSummary {
+        let mut summary = TxSummary::default();
+        for primitive in self.primitives.iter() {
+            match primitive {
+                FsPrimitive::Link { .. } | FsPrimitive::HardLink { .. } => summary.links += 1,
+                FsPrimitive::CopyFile { .. } | FsPrimitive::CopyDir { .. } => summary.copies += 1,
+                FsPrimitive::CreateDir(..) => summary.created_dirs += 1,
+                FsPrimitive::RemoveFile(_)
+                | FsPrimitive::RemoveDir(_)
+                | FsPrimitive::RemoveDirAll(_) => summary.removed += 1,
+                FsPrimitive::Rename { .. } | FsPrimitive::SetMode { .. } | FsPrimitive::Nop => {}
+            }
+        }
+        summary
+    }
+}
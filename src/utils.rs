@@ -1,9 +1,14 @@
-use std::path::PathBuf;
+use std::{
+    io::{BufRead, Write},
+    path::{Component, Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 use anyhow::Context;
 use path_absolutize::Absolutize;
+use walkdir::WalkDir;
 
-use crate::globals;
+use crate::{error::DullError, failure::Failure, globals, log};
 
 #[derive(Clone, Debug)]
 pub struct ResolvedLink {
@@ -12,14 +17,33 @@ pub struct ResolvedLink {
 }
 
 impl ResolvedLink {
+    /// Resolves the source against the current directory, and the target with `expand_target`.
     pub fn new(source: &PathBuf, target: &PathBuf) -> anyhow::Result<Self> {
         Ok(Self {
             abs_source: expand_path(source)?,
-            abs_target: expand_path(target)?,
+            abs_target: expand_target(target)?,
         })
     }
 }
 
+/// Expands a target path. Relative targets are resolved against the home directory, unless they
+/// explicitly start with `.` or `..`, in which case they are resolved against the current directory.
+pub fn expand_target(target: &PathBuf) -> anyhow::Result<PathBuf> {
+    let is_home_relative = target.is_relative()
+        && !target.to_string_lossy().starts_with('~')
+        && matches!(
+            target.components().next(),
+            Some(std::path::Component::Normal(_))
+        );
+    if is_home_relative {
+        expand_path(&PathBuf::from("~").join(target))
+    } else {
+        expand_path(target)
+    }
+}
+
+/// Expands `~` and `~user` prefixes and absolutizes the path against the current directory.
+/// Fails if the home directory of the user cannot be found.
 pub fn expand_path(path: &PathBuf) -> anyhow::Result<PathBuf> {
     let expanded_path = expanduser::expanduser(path.as_os_str().to_string_lossy())
         .context(format!("could not expand the path {:?}", path))?;
@@ -30,28 +54,310 @@ pub fn expand_path(path: &PathBuf) -> anyhow::Result<PathBuf> {
             expanded_path
         ))
         .map(|p| p.into());
+    if let Ok(absolute_path) = &absolute_path {
+        log::trace!("Expanded the path {:?} to {:?}", path, absolute_path);
+    }
     absolute_path
 }
 
+/// Returns the filenames that are never linked, i.e., the given marker files and the ignore file.
+pub fn ignore_filenames(marker_filenames: &[&str]) -> Vec<String> {
+    marker_filenames
+        .iter()
+        .copied()
+        .chain(std::iter::once(globals::DULLIGNORE_FILENAME))
+        .map(String::from)
+        .collect()
+}
+
+/// Returns true iff the file name of the given path is in the list of ignored filenames.
+pub fn is_ignored(path: &Path, ignore_filenames: &[String]) -> bool {
+    path.file_name()
+        .map(|file_name| file_name.to_string_lossy())
+        .is_some_and(|file_name| ignore_filenames.iter().any(|f| f == file_name.as_ref()))
+}
+
+/// Resolves the symlinks in the existing ancestors of the given absolute path, without following the
+/// path itself if it is a symlink.
+pub fn resolve_ancestors(path: &Path) -> PathBuf {
+    let (Some(parent), Some(file_name)) = (path.parent(), path.file_name()) else {
+        return path.to_path_buf();
+    };
+    parent
+        .ancestors()
+        .find_map(|ancestor| {
+            let canon_ancestor = ancestor.canonicalize().ok()?;
+            let rest = parent.strip_prefix(ancestor).ok()?;
+            Some(canon_ancestor.join(rest))
+        })
+        .unwrap_or_else(|| parent.to_path_buf())
+        .join(file_name)
+}
+
+/// Returns true iff the current user can create and remove the entries of the directory at `dir`.
+pub fn is_writable(dir: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+    let Ok(dir) = std::ffi::CString::new(dir.as_os_str().as_bytes()) else {
+        return false;
+    };
+    unsafe { libc::access(dir.as_ptr(), libc::W_OK | libc::X_OK) == 0 }
+}
+
+/// Returns the device of the given path, or of its nearest existing ancestor if it does not exist.
+pub fn device_of(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    path.ancestors()
+        .find_map(|ancestor| ancestor.symlink_metadata().ok())
+        .map(|metadata| metadata.dev())
+}
+
+/// Returns the relative path that a symlink at `link` would use to point to `to`, both of which must
+/// be absolute. The ancestors of both are resolved, so that the path is interpreted from the actual
+/// directory of the link. Returns `None` if the paths share nothing but the root.
+pub fn relative_link(link: &Path, to: &Path) -> Option<PathBuf> {
+    if !link.is_absolute() || !to.is_absolute() {
+        return None;
+    }
+    let link = resolve_ancestors(link);
+    let from = link.parent()?;
+    let to = resolve_ancestors(to);
+    let common = from
+        .components()
+        .zip(to.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    // Only the root directory is shared.
+    if common <= 1 {
+        return None;
+    }
+    let ups = from.components().count() - common;
+    let mut relative: PathBuf = std::iter::repeat_n(Component::ParentDir, ups).collect();
+    relative.extend(to.components().skip(common));
+    Some(relative)
+}
+
+/// Returns the path that the symlink at `link` points to, interpreting a relative destination from the
+/// directory of the link.
+pub fn link_destination(link: &Path) -> Option<PathBuf> {
+    let linked = link.read_link().ok()?;
+    if linked.is_absolute() {
+        return Some(linked);
+    }
+    Some(link.parent()?.join(linked))
+}
+
+/// Returns true iff the symlink at `link` points to `source`, either by its absolute path, or through
+/// a relative path that resolves to it.
+pub fn links_to(link: &Path, source: &Path) -> bool {
+    let Ok(linked) = link.read_link() else {
+        return false;
+    };
+    if linked.is_absolute() {
+        return linked == source;
+    }
+    let resolved = link_destination(link).and_then(|dest| dest.canonicalize().ok());
+    resolved.is_some() && resolved == source.canonicalize().ok()
+}
+
+/// Returns the seconds since the Unix epoch.
+pub fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Returns the current UTC date formatted as `YYYYMMDD`.
+pub fn date_name() -> String {
+    let (year, month, day, ..) = civil_time(now_secs());
+    format!("{:04}{:02}{:02}", year, month, day)
+}
+
+/// Returns the current UTC time formatted as `YYYYMMDD-HHMMSS`.
+pub fn timestamp_name() -> String {
+    let (year, month, day, hour, minute, second) = civil_time(now_secs());
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year, month, day, hour, minute, second
+    )
+}
+
+/// Formats the given seconds since the Unix epoch as `YYYY-MM-DD HH:MM:SS` in UTC.
+pub fn format_timestamp(secs: u64) -> String {
+    let (year, month, day, hour, minute, second) = civil_time(secs);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year, month, day, hour, minute, second
+    )
+}
+
+/// Parses a duration such as `30s`, `15m`, `12h`, `7d` or `2w`.
+pub fn parse_duration(s: &str) -> anyhow::Result<std::time::Duration> {
+    let unit_start = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (amount, unit) = s.split_at(unit_start);
+    let amount: u64 = amount
+        .parse()
+        .context(format!("invalid duration {:?}, expected e.g. `7d`", s))?;
+    let unit_secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        "w" => 7 * 86400,
+        _ => anyhow::bail!(
+            "invalid duration unit {:?}, expected one of s, m, h, d, w",
+            unit
+        ),
+    };
+    Ok(std::time::Duration::from_secs(amount * unit_secs))
+}
+
+/// Converts the seconds since the Unix epoch into the UTC year, month, day, hour, minute and second.
+fn civil_time(secs: u64) -> (i64, i64, i64, u64, u64, u64) {
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+    // Convert the days since the epoch into a civil date (Howard Hinnant's algorithm).
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+    )
+}
+
+/// Returns the 64-bit FNV-1a hash of the contents of the file at the given path.
+pub fn content_hash(path: &Path) -> anyhow::Result<u64> {
+    let file = std::fs::File::open(path).context(format!("could not open {:?}", path))?;
+    let mut reader = std::io::BufReader::new(file);
+    let mut hash: u64 = 0xcbf29ce484222325;
+    loop {
+        let buf = reader
+            .fill_buf()
+            .context(format!("could not read {:?}", path))?;
+        if buf.is_empty() {
+            return Ok(hash);
+        }
+        for byte in buf {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        let len = buf.len();
+        reader.consume(len);
+    }
+}
+
+/// Returns the total size in bytes of the files under the given path, without following symlinks.
+pub fn dir_size(path: &Path) -> u64 {
+    WalkDir::new(path)
+        .follow_links(false)
+        .into_iter()
+        .flatten()
+        .flat_map(|entry| entry.path().symlink_metadata())
+        .filter(|metadata| !metadata.is_dir())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// Returns the number of bytes available to unprivileged users on the filesystem that contains
+/// the given path, or its closest existing ancestor.
+pub fn available_space(path: &Path) -> anyhow::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let existing = path
+        .ancestors()
+        .find(|p| p.exists())
+        .context(format!("could not find an existing ancestor of {:?}", path))?;
+    let c_path = std::ffi::CString::new(existing.as_os_str().as_bytes())
+        .context(format!("invalid path {:?}", existing))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error()).context(format!(
+            "could not get the filesystem stats of {:?}",
+            existing
+        ));
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Defers the interrupts (Ctrl-C) while it is alive, so that they can be handled between the
+/// filesystem modifications. The previous handler is restored when it is dropped.
+#[derive(Debug)]
+pub struct InterruptGuard(libc::sighandler_t);
+
+impl InterruptGuard {
+    pub fn install() -> Self {
+        let handler = on_interrupt as extern "C" fn(libc::c_int);
+        let prev_handler = unsafe { libc::signal(libc::SIGINT, handler as libc::sighandler_t) };
+        Self(prev_handler)
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        unsafe { libc::signal(libc::SIGINT, self.0) };
+    }
+}
+
+/// Returns true iff an interrupt was deferred, in which case the remaining modifications should be cancelled.
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Returns the path of the latest build. Falls back to the state file in the current directory, which
+/// was used by the older versions.
 pub fn get_state() -> anyhow::Result<String> {
-    let state_file = PathBuf::from(".").join(globals::STATE_FILE_NAME);
+    let state_file = globals::state_dir().join(globals::STATE_FILE_NAME);
+    let legacy_state_file = PathBuf::from(".").join(globals::STATE_FILE_NAME);
     std::fs::read_to_string(&state_file)
+        .or_else(|_| std::fs::read_to_string(&legacy_state_file))
         .context(format!("could not get the state file {:?}", state_file))
+        .context(Failure::Precondition)
 }
 
-pub fn set_state(contents: &str) -> anyhow::Result<()> {
-    let state_file = PathBuf::from(".").join(globals::STATE_FILE_NAME);
+/// Records the given build as the latest build, by its absolute path so that it does not depend on the
+/// current directory.
+pub fn set_state(build_path: &PathBuf) -> anyhow::Result<()> {
+    let state_dir = globals::state_dir();
+    std::fs::create_dir_all(state_dir).context(format!(
+        "could not create the state directory {:?}",
+        state_dir
+    ))?;
+    let state_file = state_dir.join(globals::STATE_FILE_NAME);
+    let contents = expand_path(build_path)?.to_string_lossy().to_string();
     std::fs::write(&state_file, contents)
         .context(format!("could not set the state file {:?}", state_file))
 }
 
-pub fn copy_file_or_symlink(source: &PathBuf, target: &PathBuf) -> anyhow::Result<()> {
+/// Copies the regular file or symlink at `source` to `target`. The copied regular files keep the
+/// timestamps of their sources iff `preserve_timestamps` is set.
+pub fn copy_file_or_symlink(
+    source: &PathBuf,
+    target: &PathBuf,
+    preserve_timestamps: bool,
+) -> anyhow::Result<()> {
     if target.symlink_metadata().is_ok() {
-        anyhow::bail!("target {:?} exists", target);
+        return Err(DullError::TargetExists(target.clone()).into());
     }
     if source.is_symlink() {
+        // Dangling symlinks cannot be canonicalized, so copy their link as is.
         let canon_source = source
             .canonicalize()
+            .or_else(|_| source.read_link())
             .context(format!("could not canonicalize {:?}", source))?;
         std::os::unix::fs::symlink(&canon_source, target).context(format!(
             "could not create the link {:?} to {:?}",
@@ -60,6 +366,337 @@ pub fn copy_file_or_symlink(source: &PathBuf, target: &PathBuf) -> anyhow::Resul
     } else {
         std::fs::copy(source, target)
             .context(format!("could not copy file {:?} to {:?}", source, target))?;
+        if preserve_timestamps {
+            copy_timestamps(source, target)?;
+        }
     }
     Ok(())
 }
+
+/// Sets the access and modification times of the file at `target` to the ones of `source`.
+fn copy_timestamps(source: &Path, target: &Path) -> anyhow::Result<()> {
+    let metadata = source
+        .metadata()
+        .context(format!("could not read the metadata of {:?}", source))?;
+    let times = std::fs::FileTimes::new()
+        .set_accessed(metadata.accessed()?)
+        .set_modified(metadata.modified()?);
+    // Setting the times only requires owning the file, so it need not be writable.
+    std::fs::File::open(target)
+        .and_then(|file| file.set_times(times))
+        .context(format!("could not set the timestamps of {:?}", target))
+}
+
+/// Hard links the regular file at `source` to `target`. Copies the file instead if they are on
+/// different filesystems.
+pub fn hard_link_or_copy(source: &PathBuf, target: &PathBuf) -> anyhow::Result<()> {
+    if target.symlink_metadata().is_ok() {
+        return Err(DullError::TargetExists(target.clone()).into());
+    }
+    match std::fs::hard_link(source, target) {
+        Err(err) if err.kind() == std::io::ErrorKind::CrossesDevices => {}
+        res => {
+            return res.context(format!(
+                "could not create the hard link {:?} to {:?}",
+                target, source
+            ))
+        }
+    }
+    copy_file_or_symlink(source, target, globals::preserve_timestamps())?;
+    copy_permissions(source, target)
+}
+
+/// Carries over the permissions of the regular file at `source` to `target`.
+pub fn copy_permissions(source: &PathBuf, target: &PathBuf) -> anyhow::Result<()> {
+    let source_metadata = std::fs::symlink_metadata(source)
+        .context(format!("could not read the metadata of {:?}", source))?;
+    if source_metadata.is_file() {
+        std::fs::set_permissions(target, source_metadata.permissions())
+            .context(format!("could not set the permissions of {:?}", target))?;
+    }
+    Ok(())
+}
+
+/// Returns the first symlink under `root` that points back to one of its own ancestors, together
+/// with the directory it resolves to. Following such a link would never terminate. A symlink that
+/// never resolves, e.g., one that points to itself, is returned along with itself.
+pub fn find_symlink_cycle(root: &Path) -> Option<(PathBuf, PathBuf)> {
+    WalkDir::new(root)
+        .follow_root_links(true)
+        .follow_links(false)
+        .into_iter()
+        .flatten()
+        .filter(|entry| entry.path_is_symlink())
+        .find_map(|entry| {
+            let link = entry.into_path();
+            let resolved = match link.canonicalize() {
+                Err(err) if err.raw_os_error() == Some(libc::ELOOP) => {
+                    return Some((link.clone(), link))
+                }
+                resolved => resolved.ok()?,
+            };
+            let parent = link.parent()?.canonicalize().ok()?;
+            (resolved.is_dir() && parent.starts_with(&resolved)).then_some((link, resolved))
+        })
+}
+
+/// Holds the lock of the data directory, releasing it when dropped.
+pub struct LockGuard(PathBuf);
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Returns true iff the lock at `lock_path` was left behind by a process that is no longer running.
+fn is_stale_lock(lock_path: &Path) -> bool {
+    let Some(pid) = std::fs::read_to_string(lock_path)
+        .ok()
+        .and_then(|contents| contents.trim().parse::<libc::pid_t>().ok())
+        .filter(|pid| *pid > 0)
+    else {
+        // The owner may not have written its PID yet.
+        return false;
+    };
+    // The null signal only checks whether the process exists.
+    let res = unsafe { libc::kill(pid, 0) };
+    res != 0 && std::io::Error::last_os_error().raw_os_error() == Some(libc::ESRCH)
+}
+
+/// Acquires the lock of the data directory, failing fast if another invocation holds it.
+pub fn acquire_lock() -> anyhow::Result<LockGuard> {
+    let data_dir = globals::data_dir();
+    std::fs::create_dir_all(data_dir).context(format!(
+        "could not create the data directory {:?}",
+        data_dir
+    ))?;
+    let lock_path = data_dir.join(globals::LOCK_FILE_NAME);
+    let mut lock_file = loop {
+        match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&lock_path)
+        {
+            Ok(lock_file) => break lock_file,
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                if !is_stale_lock(&lock_path) {
+                    return Err(DullError::Locked(lock_path)).context(Failure::Precondition);
+                }
+                log::warning!("Taking over the stale lock {:?}", lock_path);
+                std::fs::remove_file(&lock_path)
+                    .context(format!("could not remove the stale lock {:?}", lock_path))?;
+            }
+            Err(err) => {
+                return Err(err).context(format!("could not create the lock file {:?}", lock_path))
+            }
+        }
+    };
+    // Record the owner for diagnosing stale locks.
+    let _ = writeln!(lock_file, "{}", std::process::id());
+    Ok(LockGuard(lock_path))
+}
+
+/// Moves the file, symlink or directory at `from` to the free path `to`. Falls back to copying and
+/// removing across filesystem boundaries.
+pub fn move_path(from: &PathBuf, to: &PathBuf) -> anyhow::Result<()> {
+    if to.symlink_metadata().is_ok() {
+        return Err(DullError::TargetExists(to.clone()).into());
+    }
+    match std::fs::rename(from, to) {
+        Err(err) if err.kind() == std::io::ErrorKind::CrossesDevices => {}
+        res => return res.context(format!("could not rename {:?} to {:?}", from, to)),
+    }
+    let metadata = from
+        .symlink_metadata()
+        .context(format!("could not read the metadata of {:?}", from))?;
+    if metadata.is_dir() {
+        copy_dir_all(from, to, &[])?;
+        std::fs::remove_dir_all(from).context(format!("could not remove {:?}", from))
+    } else {
+        copy_file_or_symlink(from, to, globals::preserve_timestamps())?;
+        copy_permissions(from, to)?;
+        std::fs::remove_file(from).context(format!("could not remove {:?}", from))
+    }
+}
+
+/// Recursively copies the directory at `source` to the free path `target`, leaving out the files and
+/// directories with the ignored filenames at any depth.
+pub fn copy_dir_all(
+    source: &PathBuf,
+    target: &Path,
+    ignore_filenames: &[String],
+) -> anyhow::Result<()> {
+    if target.symlink_metadata().is_ok() {
+        return Err(DullError::TargetExists(target.to_path_buf()).into());
+    }
+    let inner_sources = WalkDir::new(source)
+        .follow_root_links(true)
+        .follow_links(false)
+        .into_iter()
+        .filter_entry(|entry| entry.depth() == 0 || !is_ignored(entry.path(), ignore_filenames))
+        .flatten()
+        .map(|p| p.path().to_path_buf());
+    for inner_source in inner_sources {
+        let inner_target = target.join(
+            inner_source
+                .strip_prefix(source)
+                .context(format!("{:?} is not under {:?}", inner_source, source))?,
+        );
+        if inner_source == *source || (inner_source.is_dir() && !inner_source.is_symlink()) {
+            std::fs::create_dir(&inner_target)
+                .context(format!("could not create {:?}", inner_target))?;
+        } else {
+            copy_file_or_symlink(&inner_source, &inner_target, globals::preserve_timestamps())?;
+            copy_permissions(&inner_source, &inner_target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Serializes the tests that use the data directory, which is shared by the whole process.
+#[cfg(test)]
+static TEST_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// An empty directory for a test, which is removed along with the data directory when dropped.
+#[cfg(test)]
+pub struct TestDir {
+    path: PathBuf,
+    _lock: std::sync::MutexGuard<'static, ()>,
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+        let _ = std::fs::remove_dir_all(globals::data_dir());
+    }
+}
+
+/// Creates an empty directory for a test, and points the data directory of dull to a directory of
+/// the process. The tests holding a directory run one at a time, so each starts with an empty data
+/// directory.
+#[cfg(test)]
+pub fn test_dir() -> TestDir {
+    // A failed test poisons the lock, but the directories are still cleaned up.
+    let lock = TEST_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+    let tmp_dir = std::env::temp_dir();
+    globals::init_data_dir(Some(
+        tmp_dir.join(format!("dull-test-data-{}", std::process::id())),
+    ));
+    let path = tmp_dir.join(format!("dull-test-{}", rand::random::<u64>()));
+    std::fs::create_dir_all(&path).expect("could not create the test directory");
+    TestDir { path, _lock: lock }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the name and the home directory of the current user.
+    fn current_user() -> (String, PathBuf) {
+        // SAFETY: the returned entry is only read before any other passwd lookup.
+        unsafe {
+            let passwd = libc::getpwuid(libc::getuid());
+            assert!(!passwd.is_null());
+            let name = std::ffi::CStr::from_ptr((*passwd).pw_name);
+            let dir = std::ffi::CStr::from_ptr((*passwd).pw_dir);
+            (
+                name.to_string_lossy().into_owned(),
+                PathBuf::from(dir.to_string_lossy().into_owned()),
+            )
+        }
+    }
+
+    #[test]
+    fn expands_the_home_prefixes() {
+        let home = expand_path(&PathBuf::from("~")).unwrap();
+        assert!(home.is_absolute());
+        assert_eq!(
+            expand_path(&PathBuf::from("~/.config/nvim")).unwrap(),
+            home.join(".config/nvim")
+        );
+        let (user, user_home) = current_user();
+        assert_eq!(
+            expand_path(&PathBuf::from(format!("~{}/.config", user))).unwrap(),
+            user_home.join(".config")
+        );
+        assert_eq!(
+            expand_target(&PathBuf::from(format!("~{}", user))).unwrap(),
+            user_home
+        );
+    }
+
+    #[test]
+    fn expands_the_bare_relative_paths() {
+        let home = expand_path(&PathBuf::from("~")).unwrap();
+        let cwd = std::env::current_dir().unwrap();
+        // Targets are relative to the home directory, other paths to the current directory.
+        assert_eq!(
+            expand_target(&PathBuf::from(".config/nvim")).unwrap(),
+            home.join(".config/nvim")
+        );
+        assert_eq!(
+            expand_path(&PathBuf::from(".config/nvim")).unwrap(),
+            cwd.join(".config/nvim")
+        );
+        assert_eq!(
+            expand_target(&PathBuf::from("./out")).unwrap(),
+            cwd.join("out")
+        );
+        assert_eq!(
+            expand_target(&PathBuf::from("/etc")).unwrap(),
+            PathBuf::from("/etc")
+        );
+    }
+
+    #[test]
+    fn copies_the_timestamps_only_if_asked() {
+        let dir = test_dir();
+        let source = dir.join("config");
+        std::fs::write(&source, "contents").unwrap();
+        // Move the source into the past, so that a fresh copy would differ.
+        let modified = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1 << 30);
+        std::fs::File::options()
+            .write(true)
+            .open(&source)
+            .and_then(|file| file.set_modified(modified))
+            .unwrap();
+        let modified_of = |path: &PathBuf| path.metadata().unwrap().modified().unwrap();
+        copy_file_or_symlink(&source, &dir.join("preserved"), true).unwrap();
+        assert_eq!(modified_of(&dir.join("preserved")), modified);
+        copy_file_or_symlink(&source, &dir.join("fresh"), false).unwrap();
+        assert!(modified_of(&dir.join("fresh")) > modified);
+    }
+
+    #[test]
+    fn finds_self_referential_symlinks() {
+        let dir = test_dir();
+        std::fs::create_dir(dir.join("module")).unwrap();
+        std::fs::write(dir.join("module/file"), "").unwrap();
+        assert_eq!(find_symlink_cycle(&dir.join("module")), None);
+        // A link to its own directory.
+        std::os::unix::fs::symlink(".", dir.join("module/itself")).unwrap();
+        let module = dir.join("module").canonicalize().unwrap();
+        assert_eq!(
+            find_symlink_cycle(&dir.join("module")),
+            Some((dir.join("module/itself"), module))
+        );
+        std::fs::remove_file(dir.join("module/itself")).unwrap();
+        // A link to itself.
+        std::os::unix::fs::symlink("loop", dir.join("module/loop")).unwrap();
+        assert_eq!(
+            find_symlink_cycle(&dir.join("module")),
+            Some((dir.join("module/loop"), dir.join("module/loop")))
+        );
+    }
+}
@@ -1,60 +1,310 @@
-use std::{marker::PhantomData, path::PathBuf};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    marker::PhantomData,
+    path::{Path, PathBuf},
+};
 
 use anyhow::Context;
 use itertools::Itertools;
 
-use rand::Rng;
 use walkdir::WalkDir;
 
 use crate::{
-    config_parser::{ModuleConfig, ResolvedConfig},
-    globals,
+    config_parser::{DeployMode, GlobalConfig, ModuleConfig, ResolvedConfig, Settings},
+    error::DullError,
+    failure::Failure,
+    globals, log,
+    manifest::{BuildInfo, FileHash, Manifest, ManifestEntry},
     module_parser::ModuleParser,
-    transaction::{ActualFilesystem, TxBuilder, TxProcessor},
+    transaction::{ActualFilesystem, DisplayMode, Filesystem, Transaction, TxBuilder, TxProcessor},
     utils,
 };
 
 pub struct VirtualSystemBuilder<'a> {
     modules_config: &'a [ModuleConfig],
+    global_config: &'a GlobalConfig,
+    settings: &'a Settings,
+    ignore_filenames: Vec<String>,
+    /// If set, the modules that cannot be parsed are skipped rather than failing the build.
+    keep_going: bool,
 }
 
 impl<'a> VirtualSystemBuilder<'a> {
     pub fn from_config(config: &'a ResolvedConfig) -> Self {
         Self {
             modules_config: &config.modules,
+            global_config: &config.global,
+            settings: &config.settings,
+            ignore_filenames: config.ignore_filenames(),
+            keep_going: false,
         }
     }
 
-    pub fn build(self, build_name: Option<String>, verbose: bool) -> anyhow::Result<PathBuf> {
+    /// Skips the modules that cannot be parsed, only failing if none of them can be parsed.
+    pub fn keep_going(self, keep_going: bool) -> Self {
+        Self { keep_going, ..self }
+    }
+
+    /// Warns about the modules whose sources lie under the sources of other modules, as the files
+    /// under the nested sources are collected by both modules.
+    fn warn_nested_sources(&self) {
+        let sources = self
+            .modules_config
+            .iter()
+            .map(|module_config| {
+                let source = &module_config.source;
+                let resolved = source
+                    .canonicalize()
+                    .or_else(|_| utils::expand_path(source))
+                    .unwrap_or(source.clone());
+                (resolved, module_config)
+            })
+            .collect_vec();
+        for (inner_source, inner) in sources.iter() {
+            for (outer_source, outer) in sources.iter() {
+                if inner_source != outer_source && inner_source.starts_with(outer_source) {
+                    log::warning!(
+                        "The source of the module {} lies under the source of the module {}, so its files are collected by both",
+                        inner.describe(),
+                        outer.describe()
+                    );
+                }
+            }
+        }
+    }
+
+    /// Parses the modules and resolves their links, along with the manifest that describes them and the
+    /// configurations of the parsed modules, which leave out the ones skipped due to `keep_going`.
+    fn resolve_links(
+        &self,
+    ) -> anyhow::Result<(Manifest, Vec<utils::ResolvedLink>, Vec<&'a ModuleConfig>)> {
+        self.warn_nested_sources();
         let mut parsed_modules = vec![];
+        let mut skipped_modules = vec![];
         for module_config in self.modules_config.iter() {
-            let parsed_module = ModuleParser::from_config(module_config).parse()?;
-            parsed_modules.push(parsed_module);
+            log::info!("Parsing module {:?}", module_config.source);
+            let result = ModuleParser::from_config(
+                module_config,
+                self.global_config,
+                &self.ignore_filenames,
+            )
+            .parse()
+            .context(format!(
+                "could not parse the module {}",
+                module_config.describe()
+            ));
+            match result {
+                Ok(parsed_module) => parsed_modules.push((parsed_module, module_config)),
+                Err(err) if self.keep_going => {
+                    log::warning!("Skipping the module due to error: {:?}", err);
+                    skipped_modules.push(module_config.describe());
+                }
+                Err(err) => return Err(err),
+            }
         }
-        let generated_links = parsed_modules
-            .into_iter()
-            .zip(self.modules_config.iter())
-            .flat_map(|(m, conf)| m.emplace(&conf.target))
-            .collect_vec();
+        if !skipped_modules.is_empty() {
+            if parsed_modules.is_empty() {
+                anyhow::bail!(
+                    "none of the {} modules could be parsed",
+                    skipped_modules.len()
+                );
+            }
+            log::warning!("Skipped {} modules:", skipped_modules.len());
+            for skipped_module in skipped_modules {
+                log::warning!(" - {}", skipped_module);
+            }
+        }
+        let mut manifest = Manifest {
+            dir_modes: self
+                .global_config
+                .dir_modes()
+                .context("invalid directory permissions")?,
+            settings: self.settings.clone(),
+            ..Default::default()
+        };
+        let mut generated_links = vec![];
+        let module_configs = parsed_modules.iter().map(|(_, conf)| *conf).collect_vec();
+        // Maps the targets to the modules that link them, to report the conflicting modules.
+        let mut owners: HashMap<PathBuf, &ModuleConfig> = HashMap::new();
+        let mut conflicts = vec![];
+        for (parsed_module, module_config) in parsed_modules {
+            let links = parsed_module
+                .emplace(&module_config.target)
+                .context(format!(
+                    "could not resolve the targets of the module {}",
+                    module_config.describe()
+                ))?;
+            let permission_patterns = module_config.permission_patterns()?;
+            for link in links {
+                manifest.entries.push(ManifestEntry {
+                    virtual_target: link
+                        .abs_target
+                        .strip_prefix("/")
+                        .unwrap_or(&link.abs_target)
+                        .to_path_buf(),
+                    abs_source: link.abs_source.clone(),
+                    abs_target: link.abs_target.clone(),
+                    module: Some(module_config.name()),
+                    origin: Some(module_config.origin.clone()),
+                    mode: module_config.mode,
+                    source_hash: link
+                        .abs_source
+                        .is_file()
+                        .then(|| FileHash::compute(&link.abs_source).ok())
+                        .flatten(),
+                    permissions: permission_patterns.of(&link.abs_source),
+                });
+                match owners.get(&link.abs_target) {
+                    Some(owner) if owner.source != module_config.source => conflicts.push((
+                        owner.describe(),
+                        module_config.describe(),
+                        link.abs_target.clone(),
+                    )),
+                    Some(_) => {}
+                    None => {
+                        owners.insert(link.abs_target.clone(), module_config);
+                    }
+                }
+                generated_links.push(link);
+            }
+        }
+        if !conflicts.is_empty() {
+            let report = conflicts
+                .into_iter()
+                .unique_by(|(first, second, _)| (first.clone(), second.clone()))
+                .map(|(first, second, target)| {
+                    format!(" - {} and {} both link {:?}", first, second, target)
+                })
+                .join("\n");
+            return Err(anyhow::anyhow!("conflicting modules:\n{}", report))
+                .context(Failure::Config);
+        }
+        Ok((manifest, generated_links, module_configs))
+    }
+
+    /// Parses the modules and resolves their links without writing anything, and returns the number
+    /// of links. Fails if two links have the same target, or if a target lies under another one.
+    pub fn check(self) -> anyhow::Result<usize> {
+        let (_, links, _) = self.resolve_links()?;
+        let targets: HashSet<&Path> = links.iter().map(|link| link.abs_target.as_path()).collect();
+        let mut conflicts = vec![];
+        if targets.len() < links.len() {
+            links
+                .iter()
+                .map(|link| &link.abs_target)
+                .duplicates()
+                .for_each(|target| {
+                    conflicts.push(format!(" - {:?} is linked more than once", target))
+                });
+        }
+        for link in links.iter() {
+            if let Some(ancestor) = link
+                .abs_target
+                .ancestors()
+                .skip(1)
+                .find(|ancestor| targets.contains(ancestor))
+            {
+                conflicts.push(format!(
+                    " - {:?} lies under the linked {:?}",
+                    link.abs_target, ancestor
+                ));
+            }
+        }
+        if !conflicts.is_empty() {
+            return Err(anyhow::anyhow!(
+                "conflicting links:\n{}",
+                conflicts.join("\n")
+            ))
+            .context(Failure::Precondition);
+        }
+        Ok(links.len())
+    }
+
+    /// Builds the virtual system, named after the current time unless `build_name` is given.
+    /// An existing build with the same name is removed first if `overwrite` is set.
+    pub fn build(
+        self,
+        build_name: Option<String>,
+        overwrite: bool,
+        display_mode: DisplayMode,
+    ) -> anyhow::Result<PathBuf> {
+        let (manifest, generated_links, module_configs) = self.resolve_links()?;
+        let builds_dir = globals::builds_dir();
         let effective_build_name = if let Some(build_name) = build_name {
             build_name
         } else {
-            format!("{}", rand::thread_rng().gen::<u32>())
+            // Builds made within the same second are told apart by a suffix.
+            let timestamp = utils::timestamp_name();
+            std::iter::once(timestamp.clone())
+                .chain((2..).map(|i| format!("{}-{}", timestamp, i)))
+                .find(|name| builds_dir.join(name).symlink_metadata().is_err())
+                .unwrap_or(timestamp)
         };
-        // Generate the virtual system.
-        let build_dir = PathBuf::from("builds").join(&effective_build_name);
-        // Generate the transaction and run it unatomically.
+        let build_dir = builds_dir.join(&effective_build_name);
+        let mut tx_proc = TxProcessor::new("build", display_mode);
+        if build_dir.symlink_metadata().is_ok() {
+            if !overwrite {
+                return Err(DullError::BuildExists(build_dir)).context(Failure::Precondition);
+            }
+            let existing_build = VirtualSystem::read(build_dir.clone())
+                .context(format!("{:?} is not a build", build_dir))?;
+            if existing_build.is_deployed() {
+                return Err(DullError::BuildDeployed(build_dir)).context(Failure::Precondition);
+            }
+            log::info!("Removing the existing build {:?}", build_dir);
+            existing_build.remove(&mut tx_proc)?;
+        }
+        // Generate the virtual system. The builds directory is created beforehand so that undoing
+        // the build never attempts to remove it.
+        std::fs::create_dir_all(&builds_dir).context(format!(
+            "could not create the builds directory {:?}",
+            builds_dir
+        ))?;
+        // Generate the transaction and run it atomically so that a failed build is rolled back, along
+        // with the removal of the existing build.
         let mut txb = TxBuilder::empty();
         let fs = ActualFilesystem;
+        txb.ensure_dirs(utils::expand_path(&build_dir)?, &fs)?;
         txb.create_links(build_dir.clone(), generated_links, &fs)?;
-        txb.build("Build")?
-            .run_haphazard(verbose)
-            .context("build failed")?;
+        let tx = txb.build("Build")?;
+        let summary = tx.summary();
+        tx_proc.run_required(tx)?;
+        log::info!("Built: {}", summary);
+        // Write the location of the undo transaction so that the build can be removed later.
+        let undo_path = build_dir.join(globals::UNDO_FILE_NAME);
+        let undo_tx_dir = tx_proc
+            .tx_dirs()
+            .last()
+            .context("could not find the undo transaction of the build")?;
+        let undo_tx_path = utils::expand_path(&undo_tx_dir.join("tx"))?;
+        std::fs::write(&undo_path, undo_tx_path.to_string_lossy().as_ref()).context(format!(
+            "could not write the undo information at {:?}",
+            undo_path
+        ))?;
         // Write the build information
-        let build_info_path = build_dir.join(globals::BUILD_FILE_NAME);
-        std::fs::write(&build_info_path, format!("{}", effective_build_name)).context(format!(
-            "could not generate the build information at {:?}",
-            build_info_path
+        BuildInfo::new(
+            effective_build_name,
+            module_configs.len(),
+            manifest.entries.len(),
+        )
+        .write(&build_dir)?;
+        manifest.write(&build_dir)?;
+        // Write the module sources so that the files owned by this build can be recognized.
+        let modules_path = build_dir.join(globals::MODULES_FILE_NAME);
+        let module_sources = module_configs
+            .iter()
+            .flat_map(|conf| utils::expand_path(&conf.source))
+            .map(|p| p.to_string_lossy().to_string())
+            .join("\n");
+        std::fs::write(&modules_path, module_sources).context(format!(
+            "could not write the module sources at {:?}",
+            modules_path
+        ))?;
+        // Write the ignored filenames so that the deployment can respect them.
+        let ignorelist_path = build_dir.join(globals::IGNORELIST_FILE_NAME);
+        std::fs::write(&ignorelist_path, self.ignore_filenames.join("\n")).context(format!(
+            "could not write the ignore list at {:?}",
+            ignorelist_path
         ))?;
         Ok(build_dir)
     }
@@ -63,85 +313,687 @@ impl<'a> VirtualSystemBuilder<'a> {
 pub struct Deployable;
 pub struct Undeployable;
 
+/// Denotes the state of a leaf of a virtual system in the actual filesystem.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LinkState {
+    /// The target is a symlink to the leaf source, or an unchanged copy of a file or symlink source.
+    Linked,
+    /// The target is a symlink to a different path.
+    WrongTarget(PathBuf),
+    /// The target does not exist.
+    Missing,
+    /// The target exists but is neither a symlink nor an unchanged copy.
+    TargetOccupied,
+}
+
 #[derive(Clone, Debug)]
 pub struct VirtualSystem<T> {
     pub path: PathBuf,
     pub pd: PhantomData<T>,
+    pub info: BuildInfo,
+    manifest: Option<Manifest>,
+    /// The root under which the encoded targets of the leaves are interpreted.
+    target_root: PathBuf,
+    /// The targets whose leaves are left out of the deployment.
+    skipped_targets: HashSet<PathBuf>,
+    /// When non-empty, only the leaves of these modules are considered.
+    only_modules: Vec<String>,
+    /// When set, only the leaves whose targets lie under this prefix are considered.
+    under: Option<PathBuf>,
+    /// The leaves whose targets or their ancestors match one of these patterns are left out.
+    excluded_targets: Vec<glob::Pattern>,
+    /// If set, the soft deployed leaves whose sources are on a different device than their targets
+    /// are copied instead of being linked.
+    copy_cross_device: bool,
+    /// If set, the soft deployed leaves are linked through relative paths from their targets.
+    relative_links: bool,
 }
 
 impl VirtualSystem<Undeployable> {
     /// Reads the virtual system at the given path.
     pub fn read(path: PathBuf) -> anyhow::Result<Self> {
-        let build_file_path = path.join(globals::BUILD_FILE_NAME);
-        std::fs::read_to_string(&build_file_path).context(format!(
-            "could not read the build file {:?}",
-            build_file_path
-        ))?;
+        let info = BuildInfo::read(&path).context(Failure::Precondition)?;
+        if !info.is_compatible() {
+            return Err(anyhow::anyhow!(
+                "the build {:?} was created by dull {}, which is incompatible with dull {}",
+                path,
+                info.version.as_deref().unwrap_or_default(),
+                env!("CARGO_PKG_VERSION")
+            ))
+            .context(Failure::Precondition);
+        }
+        let manifest = Manifest::read(&path)?;
         Ok(Self {
             path,
             pd: Default::default(),
+            info,
+            manifest,
+            target_root: PathBuf::from("/"),
+            skipped_targets: Default::default(),
+            only_modules: Default::default(),
+            under: None,
+            excluded_targets: Default::default(),
+            copy_cross_device: false,
+            relative_links: false,
+        })
+    }
+
+    /// Only considers the leaves of the modules with the given names.
+    /// Fails if the build does not know the modules of its leaves, or a module is not in the build.
+    pub fn with_only_modules(self, only_modules: Vec<String>) -> anyhow::Result<Self> {
+        if only_modules.is_empty() {
+            return Ok(self);
+        }
+        let manifest = self.manifest.as_ref().context(format!(
+            "the build {:?} does not record its modules, rebuild it to select modules",
+            self.path
+        ))?;
+        let module_names = manifest.module_names();
+        if let Some(unknown) = only_modules
+            .iter()
+            .find(|name| !module_names.contains(name.as_str()))
+        {
+            anyhow::bail!(
+                "the build {:?} has no module named {:?}",
+                self.path,
+                unknown
+            );
+        }
+        Ok(Self {
+            only_modules,
+            ..self
         })
     }
+
+    /// Only considers the leaves whose targets lie under the given prefix, e.g., `/etc`.
+    pub fn with_under(self, under: &PathBuf) -> anyhow::Result<Self> {
+        Ok(Self {
+            under: Some(utils::expand_path(under)?),
+            ..self
+        })
+    }
+
+    /// Leaves out the leaves whose targets match one of the given glob patterns, along with the
+    /// targets under the matching directories, e.g., `~/.config/secret`.
+    pub fn with_excluded_targets(self, patterns: &[String]) -> anyhow::Result<Self> {
+        let excluded_targets = patterns
+            .iter()
+            .map(|pattern| {
+                let expanded = utils::expand_path(&PathBuf::from(pattern))?;
+                glob::Pattern::new(&expanded.to_string_lossy())
+                    .context(format!("invalid target pattern {:?}", pattern))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self {
+            excluded_targets,
+            ..self
+        })
+    }
+
+    /// Copies the soft deployed leaves whose sources are on a different device than their targets
+    /// instead of linking them, so that they do not break when the device is unmounted.
+    pub fn with_copy_cross_device(self, copy_cross_device: bool) -> Self {
+        Self {
+            copy_cross_device,
+            ..self
+        }
+    }
+
+    /// Links the soft deployed leaves through relative paths, so that the targets keep working when
+    /// they are moved along with the modules.
+    pub fn with_relative_links(self, relative_links: bool) -> Self {
+        Self {
+            relative_links,
+            ..self
+        }
+    }
+
+    /// Interprets the targets of the leaves under `target_root` instead of `/`.
+    pub fn with_target_root(self, target_root: PathBuf) -> Self {
+        Self {
+            target_root,
+            ..self
+        }
+    }
+
+    /// Leaves the leaves with the given targets out of the deployment.
+    pub fn with_skipped_targets(self, skipped_targets: HashSet<PathBuf>) -> Self {
+        Self {
+            skipped_targets,
+            ..self
+        }
+    }
 }
 
+/// A leaf along with its absolute source and target paths.
+type ParsedLeaf = (PathBuf, PathBuf, PathBuf);
+
 impl<T> VirtualSystem<T> {
-    /// From a leaf node, extracts and returns the absolute source and target paths.
-    fn parse_leaf(&self, leaf: &PathBuf) -> anyhow::Result<(PathBuf, PathBuf)> {
+    /// Returns the filenames that were ignored while building this virtual system.
+    /// Falls back to the default ignored filenames for older builds.
+    pub fn ignore_filenames(&self) -> Vec<String> {
+        let ignorelist_path = self.path.join(globals::IGNORELIST_FILE_NAME);
+        match std::fs::read_to_string(ignorelist_path) {
+            Ok(contents) => contents
+                .lines()
+                .filter(|line| !line.is_empty())
+                .map(|line| line.to_string())
+                .collect_vec(),
+            Err(_) => globals::DEFAULT_IGNOREFILES
+                .iter()
+                .map(|f| f.to_string())
+                .collect_vec(),
+        }
+    }
+
+    /// Returns the absolute paths of the sources of the modules that were used to build this virtual system.
+    pub fn module_sources(&self) -> Vec<PathBuf> {
+        let modules_path = self.path.join(globals::MODULES_FILE_NAME);
+        std::fs::read_to_string(modules_path)
+            .map(|contents| contents.lines().map(PathBuf::from).collect_vec())
+            .unwrap_or_default()
+    }
+
+    /// Returns true iff the given target is a symlink that points into one of the module sources.
+    pub fn owns(&self, target: &Path) -> bool {
+        if !target.is_symlink() {
+            return false;
+        }
+        let Some(linked) = utils::link_destination(target) else {
+            return false;
+        };
+        let linked = linked.canonicalize().unwrap_or(linked);
+        self.module_sources().iter().any(|module_source| {
+            let module_source = module_source
+                .canonicalize()
+                .unwrap_or(module_source.clone());
+            linked.starts_with(module_source)
+        })
+    }
+
+    /// Returns true iff the target is a regular file with the same contents as the source.
+    /// The hash of the source is read from `source_hash` if it is up to date.
+    fn is_unchanged(
+        source: &Path,
+        target: &Path,
+        source_hash: Option<&FileHash>,
+    ) -> anyhow::Result<bool> {
+        let (Ok(source_metadata), Ok(target_metadata)) =
+            (source.symlink_metadata(), target.symlink_metadata())
+        else {
+            return Ok(false);
+        };
+        // A copied symlink points to the resolved path of the source symlink.
+        if source_metadata.is_symlink() && target_metadata.is_symlink() {
+            let copied = source.canonicalize().or_else(|_| source.read_link());
+            return Ok(copied.is_ok_and(|copied| target.read_link().is_ok_and(|t| t == copied)));
+        }
+        if !source_metadata.is_file()
+            || !target_metadata.is_file()
+            || source_metadata.len() != target_metadata.len()
+        {
+            return Ok(false);
+        }
+        let source_hash = match source_hash {
+            Some(source_hash) => source_hash.refresh(source)?,
+            None => utils::content_hash(source)?,
+        };
+        Ok(source_hash == utils::content_hash(target)?)
+    }
+
+    /// Returns the directories of the transactions that deployed this virtual system.
+    pub fn transaction_dirs(&self) -> Vec<PathBuf> {
+        let transactions_path = self.path.join(globals::TRANSACTIONS_FILE_NAME);
+        std::fs::read_to_string(transactions_path)
+            .map(|contents| contents.lines().map(PathBuf::from).collect_vec())
+            .unwrap_or_default()
+    }
+
+    /// Returns the directory of the transaction that undoes the build of this virtual system, if it is known.
+    pub fn undo_transaction_dir(&self) -> Option<PathBuf> {
+        std::fs::read_to_string(self.path.join(globals::UNDO_FILE_NAME))
+            .ok()
+            .and_then(|undo_tx_path| PathBuf::from(undo_tx_path).parent().map(Path::to_path_buf))
+    }
+
+    /// Returns true iff this virtual system is currently deployed.
+    pub fn is_deployed(&self) -> bool {
+        self.path.join(globals::DEPLOYED_FILE_NAME).exists()
+    }
+
+    /// Returns the default mode that this virtual system was deployed in, or `None` if it is not
+    /// deployed or was deployed by an older version.
+    pub fn deployed_mode(&self) -> Option<DeployMode> {
+        std::fs::read_to_string(self.path.join(globals::DEPLOYED_FILE_NAME))
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+    }
+
+    /// Marks this virtual system as deployed in the given default mode, or as not deployed. Deployments
+    /// under a different target root are not recorded.
+    fn set_deployed(&self, deployed_mode: Option<DeployMode>) -> anyhow::Result<()> {
+        if self.target_root != Path::new("/") {
+            return Ok(());
+        }
+        let deployed_path = self.path.join(globals::DEPLOYED_FILE_NAME);
+        if let Some(deployed_mode) = deployed_mode {
+            let contents = serde_json::to_string(&deployed_mode)?;
+            std::fs::write(&deployed_path, contents).context(format!(
+                "could not mark the build as deployed at {:?}",
+                deployed_path
+            ))
+        } else if deployed_path.exists() {
+            std::fs::remove_file(&deployed_path).context(format!(
+                "could not clear the deployment mark at {:?}",
+                deployed_path
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Records the transactions run by the given processor as the ones that deployed this virtual system.
+    /// Does nothing if the processor has not run any transactions, e.g., when it is emitting a script.
+    fn record_transactions(&self, tx_proc: &TxProcessor) -> anyhow::Result<()> {
+        if tx_proc.tx_dirs().is_empty() {
+            return Ok(());
+        }
+        let transactions_path = self.path.join(globals::TRANSACTIONS_FILE_NAME);
+        let contents = tx_proc
+            .tx_dirs()
+            .iter()
+            .flat_map(utils::expand_path)
+            .map(|p| p.to_string_lossy().to_string())
+            .join("\n");
+        std::fs::write(&transactions_path, contents).context(format!(
+            "could not record the transactions at {:?}",
+            transactions_path
+        ))
+    }
+
+    /// Writes the manifest with the directories created by the deployments. Deployments under a
+    /// different target root are not recorded.
+    fn record_created_dirs(&self) -> anyhow::Result<()> {
+        match &self.manifest {
+            Some(manifest) if self.target_root == Path::new("/") => manifest.write(&self.path),
+            _ => Ok(()),
+        }
+    }
+
+    /// Plans removing the directories created by the deployments that are empty once the `removed`
+    /// paths are removed, or only contain other such directories, and adds them to `removed`. The
+    /// pre-existing directories are never removed.
+    fn plan_remove_created_dirs(
+        &self,
+        removed: &mut HashSet<PathBuf>,
+    ) -> anyhow::Result<Option<Transaction>> {
+        if self.target_root != Path::new("/") {
+            return Ok(None);
+        }
+        let Some(manifest) = &self.manifest else {
+            return Ok(None);
+        };
+        let mut txb = TxBuilder::empty();
+        // Visit the deepest directories first, so that their parents can be removed along with them.
+        for dir in manifest
+            .created_dirs
+            .iter()
+            .filter(|dir| {
+                self.under
+                    .as_ref()
+                    .is_none_or(|under| dir.starts_with(under))
+            })
+            .filter(|dir| !removed.contains(*dir))
+            .sorted_by_key(|dir| std::cmp::Reverse(dir.components().count()))
+        {
+            let Ok(entries) = dir.read_dir() else {
+                continue;
+            };
+            let is_empty = entries
+                .flatten()
+                .all(|entry| removed.contains(&entry.path()));
+            if is_empty {
+                txb.remove_dir(dir);
+                removed.insert(dir.clone());
+            }
+        }
+        if txb.len() == 0 {
+            return Ok(None);
+        }
+        txb.build("RemoveDirs").map(Some)
+    }
+
+    /// Forgets the created directories that are removed, or that were removed by the user.
+    fn forget_created_dirs(&mut self, removed: &HashSet<PathBuf>) -> anyhow::Result<()> {
+        if self.target_root != Path::new("/") {
+            return Ok(());
+        }
+        let Some(manifest) = &mut self.manifest else {
+            return Ok(());
+        };
+        manifest
+            .created_dirs
+            .retain(|dir| !removed.contains(dir) && dir.is_dir());
+        manifest.write(&self.path)
+    }
+
+    /// Returns the defaults of the deployment flags recorded in the build, or the default settings for
+    /// older builds.
+    pub fn settings(&self) -> Settings {
+        self.manifest
+            .as_ref()
+            .map(|manifest| manifest.settings.clone())
+            .unwrap_or_default()
+    }
+
+    /// Returns the permission bits of the directories created under the target prefixes, which are
+    /// interpreted under the target root.
+    fn dir_modes(&self) -> anyhow::Result<HashMap<PathBuf, u32>> {
+        let Some(manifest) = &self.manifest else {
+            return Ok(HashMap::new());
+        };
+        let mut dir_modes = HashMap::new();
+        for (prefix, mode) in manifest.dir_modes.iter() {
+            let prefix = self
+                .target_root
+                .join(prefix.strip_prefix("/").unwrap_or(prefix));
+            dir_modes.insert(utils::expand_path(&prefix)?, *mode);
+        }
+        Ok(dir_modes)
+    }
+
+    /// From a leaf node, extracts and returns the absolute target path.
+    fn leaf_target(&self, leaf: &Path) -> anyhow::Result<PathBuf> {
         // The target is already encoded in the leaf source.
-        let target = PathBuf::from("/").join(
+        let target = self.target_root.join(
             leaf.strip_prefix(&self.path)
                 .context("leaf path is malformed")?,
         );
-        let abs_target = utils::expand_path(&target)?;
+        utils::expand_path(&target)
+    }
+
+    /// From a leaf node, extracts and returns the absolute source and target paths.
+    fn parse_leaf(&self, leaf: &PathBuf) -> anyhow::Result<(PathBuf, PathBuf)> {
+        let abs_target = self.leaf_target(leaf)?;
         let abs_source = utils::expand_path(leaf)?;
         // Get the original source, pointing to the regular file in the module directory.
-        let abs_source_canon = abs_source.read_link().context(format!(
-            "could not canonicalize the source {:?}",
-            abs_source
-        ))?;
+        let abs_source_canon = abs_source
+            .read_link()
+            .context(DullError::BrokenLeaf(leaf.clone()))?;
+        log::trace!(
+            "Resolved the leaf {:?} to {:?} -> {:?}",
+            leaf,
+            abs_target,
+            abs_source_canon
+        );
         Ok((abs_source_canon, abs_target))
     }
 
-    /// Returns the leaves of the virtual system.
+    /// Parses the leaves whose sources still exist, leaving out the skipped targets.
+    /// Returns the parsed leaves as `(leaf, source, target)`, and the broken leaves along with the reason.
+    fn partition_leaves(&self) -> (Vec<ParsedLeaf>, Vec<(PathBuf, anyhow::Error)>) {
+        let mut parsed = vec![];
+        let mut broken = vec![];
+        for leaf in self.get_leaves() {
+            match self.parse_leaf(&leaf) {
+                Ok((source, _)) if source.symlink_metadata().is_err() => {
+                    let err = DullError::SourceMissing(source).into();
+                    broken.push((leaf, err));
+                }
+                Ok((_, target)) if self.skipped_targets.contains(&target) => {}
+                Ok((source, target)) => parsed.push((leaf, source, target)),
+                Err(err) => broken.push((leaf, err)),
+            }
+        }
+        (parsed, broken)
+    }
+
+    /// Returns the leaves of the virtual system, only including the selected modules if there are any.
+    /// Uses the manifest if it exists, otherwise traverses the build directory.
     fn get_leaves(&self) -> Vec<PathBuf> {
-        WalkDir::new(&self.path)
-            .follow_links(false)
-            .follow_root_links(false)
+        let leaves = if let Some(manifest) = &self.manifest {
+            manifest
+                .entries
+                .iter()
+                .filter(|entry| {
+                    self.only_modules.is_empty()
+                        || entry
+                            .module
+                            .as_ref()
+                            .is_some_and(|module| self.only_modules.contains(module))
+                })
+                .map(|entry| self.path.join(&entry.virtual_target))
+                .collect_vec()
+        } else {
+            WalkDir::new(&self.path)
+                .follow_links(false)
+                .follow_root_links(false)
+                .into_iter()
+                .flatten()
+                // Symlinks are the only leafs
+                .filter(|p| p.path_is_symlink())
+                .map(|p| p.path().to_path_buf())
+                .collect_vec()
+        };
+        // The prefix and the patterns apply to the encoded targets, regardless of the target root.
+        leaves
             .into_iter()
-            .flatten()
-            // Symlinks are the only leafs
-            .filter(|p| p.path_is_symlink())
-            .map(|p| p.path().to_path_buf())
+            .filter(|leaf| {
+                let Ok(target) = leaf.strip_prefix(&self.path) else {
+                    return false;
+                };
+                let target = Path::new("/").join(target);
+                let is_under = self
+                    .under
+                    .as_ref()
+                    .is_none_or(|under| target.starts_with(under));
+                let is_excluded = target.ancestors().any(|ancestor| {
+                    self.excluded_targets
+                        .iter()
+                        .any(|pattern| pattern.matches_path(ancestor))
+                });
+                is_under && !is_excluded
+            })
             .collect_vec()
     }
 
-    pub fn undeploy(self, tx_proc: &mut TxProcessor) -> anyhow::Result<()> {
+    /// Removes the virtual system from the filesystem by running the undo transaction of its build.
+    /// Fails if it is deployed.
+    pub fn remove(self, tx_proc: &mut TxProcessor) -> anyhow::Result<()> {
+        if self.is_deployed() {
+            return Err(DullError::BuildDeployed(self.path)).context(Failure::Precondition);
+        }
+        let undo_path = self.path.join(globals::UNDO_FILE_NAME);
+        let undo_tx_path = std::fs::read_to_string(&undo_path).context(format!(
+            "could not read the undo information at {:?}",
+            undo_path
+        ))?;
+        let undo_tx = Transaction::read(undo_tx_path.into())
+            .context("could not read the undo transaction of the build")?;
+        // The build information files are not created by the build transaction, so remove them first.
+        let mut txb = TxBuilder::empty();
+        let info_files = self
+            .path
+            .read_dir()
+            .context(format!(
+                "could not read the build directory {:?}",
+                self.path
+            ))?
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|p| p.symlink_metadata().is_ok_and(|m| m.is_file()));
+        for info_file in info_files {
+            txb.remove_file(info_file);
+        }
+        txb.build("RemoveBuildInfo")
+            .and_then(|tx| tx_proc.run_required(tx))?;
+        tx_proc.run_required(undo_tx)
+    }
+
+    /// Returns the names of the modules of the leaves along with the configuration files that define
+    /// them, sorted by name. Older builds do not record the configuration files.
+    pub fn modules(&self) -> Vec<(String, Option<PathBuf>)> {
+        self.manifest
+            .iter()
+            .flat_map(|manifest| manifest.entries.iter())
+            .flat_map(|entry| Some((entry.module.clone()?, entry.origin.clone())))
+            .unique()
+            .sorted()
+            .collect()
+    }
+
+    /// Describes the module that generated the leaf at the given target, if it is known.
+    pub fn describe_origin(&self, target: &Path) -> Option<String> {
+        let entry = self.manifest.as_ref()?.entries.iter().find(|entry| {
+            self.leaf_target(&self.path.join(&entry.virtual_target))
+                .is_ok_and(|leaf_target| leaf_target == target)
+        })?;
+        let module = entry.module.as_ref()?;
+        match &entry.origin {
+            Some(origin) => Some(format!("module {:?} defined in {:?}", module, origin)),
+            None => Some(format!("module {:?}", module)),
+        }
+    }
+
+    /// Returns the number of leaves of the virtual system.
+    pub fn num_leaves(&self) -> usize {
+        self.get_leaves().len()
+    }
+
+    /// Returns the absolute targets of the leaves.
+    pub fn targets(&self) -> anyhow::Result<HashSet<PathBuf>> {
+        self.get_leaves()
+            .iter()
+            .map(|leaf| self.leaf_target(leaf))
+            .collect()
+    }
+
+    /// Compares the leaves against the actual filesystem and returns the state of each leaf target.
+    pub fn diff(&self) -> anyhow::Result<Vec<(PathBuf, LinkState)>> {
+        let source_hashes = self
+            .manifest
+            .as_ref()
+            .map(|manifest| manifest.source_hashes())
+            .unwrap_or_default();
+        let mut states = vec![];
+        for leaf in self.get_leaves() {
+            let (source, target) = self
+                .parse_leaf(&leaf)
+                .context(format!("could not parse the leaf {:?}", leaf))?;
+            // The hard deployments leave copies of the sources at the targets.
+            let source_hash = leaf
+                .strip_prefix(&self.path)
+                .ok()
+                .and_then(|virtual_target| source_hashes.get(virtual_target));
+            let state = match target.symlink_metadata() {
+                Err(_) => LinkState::Missing,
+                Ok(metadata) if !metadata.is_symlink() => {
+                    if Self::is_unchanged(&source, &target, source_hash)? {
+                        LinkState::Linked
+                    } else {
+                        LinkState::TargetOccupied
+                    }
+                }
+                Ok(_) => {
+                    let linked = target
+                        .read_link()
+                        .context(format!("could not read the link {:?}", target))?;
+                    if utils::links_to(&target, &source)
+                        || Self::is_unchanged(&source, &target, source_hash)?
+                    {
+                        LinkState::Linked
+                    } else {
+                        LinkState::WrongTarget(linked)
+                    }
+                }
+            };
+            states.push((target, state));
+        }
+        Ok(states)
+    }
+
+    /// Returns the deployed targets that are symlinks to paths that no longer resolve, e.g., because
+    /// the module sources were moved, along with the paths they link to.
+    pub fn dangling_links(&self) -> anyhow::Result<Vec<(PathBuf, PathBuf)>> {
+        let mut dangling = vec![];
+        for leaf in self.get_leaves() {
+            let target = self.leaf_target(&leaf)?;
+            if !target.is_symlink() || target.canonicalize().is_ok() {
+                continue;
+            }
+            let linked = target
+                .read_link()
+                .context(format!("could not read the link {:?}", target))?;
+            dangling.push((target, linked));
+        }
+        Ok(dangling)
+    }
+
+    /// Removes the targets of the leaves, except for the skipped ones, along with the directories that
+    /// were created for them.
+    fn remove_targets(&mut self, tx_proc: &mut TxProcessor) -> anyhow::Result<()> {
         let mut txb = TxBuilder::empty();
         let fs = ActualFilesystem;
+        let mut removed = HashSet::new();
         let leaves = self.get_leaves();
         for leaf in leaves {
-            // The target is already encoded in the leaf source.
-            let target = PathBuf::from("/").join(
-                leaf.strip_prefix(&self.path)
-                    .context("leaf path is malformed")?,
-            );
-            let abs_target = utils::expand_path(&target)?;
+            let abs_target = self.leaf_target(&leaf)?;
+            if self.skipped_targets.contains(&abs_target) {
+                continue;
+            }
             txb.remove_any(&abs_target, &fs)?;
+            removed.insert(abs_target);
         }
-        txb.build("Undeploy")
-            .and_then(|tx| tx_proc.run_required(tx))
+        let tx = txb.build("Undeploy")?;
+        // The created directories are removed in the same transaction, so that they are restored
+        // along with the targets.
+        let tx = match self.plan_remove_created_dirs(&mut removed)? {
+            Some(dirs_tx) => tx.merge(dirs_tx)?,
+            None => tx,
+        };
+        tx_proc.run_required(tx)?;
+        self.forget_created_dirs(&removed)
+    }
+
+    pub fn undeploy(mut self, tx_proc: &mut TxProcessor) -> anyhow::Result<()> {
+        self.remove_targets(tx_proc)?;
+        // The leaves outside of the prefix are still deployed.
+        if self.under.is_some() {
+            return Ok(());
+        }
+        self.set_deployed(None)
     }
 }
 
 impl VirtualSystem<Undeployable> {
-    /// Clears the target files/folders in the actual filesystem.
-    pub fn clear_targets(self, tx_proc: &mut TxProcessor) -> anyhow::Result<Self> {
+    /// Returns the existing targets that should be cleared before the deployment.
+    /// In the safe mode, only the targets that are owned by this virtual system are returned.
+    pub fn targets_to_clear(&self, safe: bool) -> anyhow::Result<Vec<PathBuf>> {
+        let mut targets = vec![];
+        for leaf in self.get_leaves() {
+            let (_, abs_target) = self.parse_leaf(&leaf)?;
+            if abs_target.symlink_metadata().is_err() {
+                continue;
+            }
+            if safe && !self.owns(&abs_target) {
+                log::warning!("Skipping removing {:?} not owned by the build", abs_target);
+                continue;
+            }
+            targets.push(abs_target);
+        }
+        Ok(targets)
+    }
+
+    /// Clears the given target files/folders in the actual filesystem.
+    pub fn clear_targets(
+        self,
+        targets: &[PathBuf],
+        tx_proc: &mut TxProcessor,
+    ) -> anyhow::Result<Self> {
         let mut txb = TxBuilder::empty();
         let fs = ActualFilesystem;
-        let leaves = self.get_leaves();
-        for leaf in leaves {
-            let (_, abs_target) = self.parse_leaf(&leaf)?;
-            txb.remove_any(&abs_target, &fs)?;
+        for target in targets {
+            txb.remove_any(target, &fs)?;
         }
         txb.build("ClearTargets")
             .and_then(|tx| tx_proc.run_required(tx))?;
@@ -155,85 +1007,468 @@ impl VirtualSystem<Undeployable> {
         tx_proc: &mut TxProcessor,
     ) -> anyhow::Result<VirtualSystem<Deployable>> {
         let mut txb = TxBuilder::empty();
+        txb.set_dir_modes(self.dir_modes()?);
         let fs = ActualFilesystem;
-        let leaves = self.get_leaves();
-        for leaf in leaves {
-            let (_, abs_target) = self.parse_leaf(&leaf)?;
+        let (leaves, broken_leaves) = self.partition_leaves();
+        if !broken_leaves.is_empty() {
+            log::warning!("Skipping {} broken leaves:", broken_leaves.len());
+            for (leaf, err) in broken_leaves {
+                log::warning!(" - {:?}: {}", leaf, err);
+            }
+        }
+        self.check_protected_targets(&leaves)?;
+        // A script may be run with different permissions.
+        if tx_proc.script().is_none() {
+            Self::check_permissions(&leaves)?;
+        }
+        for (_, _, abs_target) in leaves {
             // Create the directories leading to the target.
             let abs_target_parent = abs_target
                 .parent()
                 .context(format!("could not get the parent of {:?}", abs_target))?;
             txb.ensure_dirs(abs_target_parent, &fs)?;
         }
+        // Remember the created directories. The ones that are later rolled back are forgotten when
+        // the directories are removed.
+        let mut manifest = self.manifest;
+        if let Some(manifest) = &mut manifest {
+            manifest.created_dirs.extend(txb.created_dirs().cloned());
+            manifest.created_dirs = manifest.created_dirs.drain(..).unique().collect();
+        }
         txb.build("Prepare")
             .and_then(|tx| tx_proc.run_required(tx))?;
-        Ok(VirtualSystem {
+        let deployable = VirtualSystem {
             path: self.path,
             pd: Default::default(),
-        })
+            info: self.info,
+            manifest,
+            target_root: self.target_root,
+            skipped_targets: self.skipped_targets,
+            only_modules: self.only_modules,
+            under: self.under,
+            excluded_targets: self.excluded_targets,
+            copy_cross_device: self.copy_cross_device,
+            relative_links: self.relative_links,
+        };
+        if !tx_proc.is_simulating() {
+            deployable.record_created_dirs()?;
+        }
+        Ok(deployable)
     }
-}
 
-impl VirtualSystem<Deployable> {
-    pub fn soft_deploy(self, tx_proc: &mut TxProcessor) -> anyhow::Result<()> {
-        let mut txb = TxBuilder::empty();
-        let leaves = self.get_leaves();
-        for leaf in leaves {
-            let (source, target) = self
-                .parse_leaf(&leaf)
-                .context(format!("could not parse the leaf {:?}", leaf))?;
-            txb.link(source, target);
+    /// Fails if a target lies under this build or the files of dull, e.g., due to a module target that
+    /// overlaps the data directory, as deploying it would corrupt the builds.
+    fn check_protected_targets(&self, leaves: &[ParsedLeaf]) -> anyhow::Result<()> {
+        let protected_paths = [
+            self.path.clone(),
+            globals::builds_dir(),
+            globals::transactions_dir(),
+            globals::state_dir().join(globals::STATE_FILE_NAME),
+        ]
+        .into_iter()
+        .flat_map(|path| utils::expand_path(&path))
+        .map(|path| utils::resolve_ancestors(&path))
+        .collect_vec();
+        for (leaf, _, abs_target) in leaves {
+            let resolved_target = utils::resolve_ancestors(abs_target);
+            if let Some(protected_path) = protected_paths
+                .iter()
+                .find(|protected_path| resolved_target.starts_with(protected_path))
+            {
+                return Err(DullError::ManagedTarget {
+                    target: abs_target.clone(),
+                    leaf: leaf.clone(),
+                    managed: protected_path.clone(),
+                })
+                .context(Failure::Precondition);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the existing targets of this virtual system that `newer` does not have, i.e., the ones
+    /// that are left behind when `newer` is deployed over this virtual system.
+    pub fn orphans(&self, newer: &VirtualSystem<Undeployable>) -> anyhow::Result<Vec<PathBuf>> {
+        let newer_targets = newer.targets()?;
+        Ok(self
+            .targets()?
+            .into_iter()
+            .filter(|target| !newer_targets.contains(target) && target.symlink_metadata().is_ok())
+            .sorted()
+            .collect_vec())
+    }
+
+    /// Removes the orphaned targets of this virtual system with respect to `newer`, and returns the
+    /// number of removed targets. If `newer` is deployed, the remaining targets belong to it, so this
+    /// virtual system is no longer marked as deployed.
+    pub fn remove_orphans(
+        self,
+        newer: &VirtualSystem<Undeployable>,
+        tx_proc: &mut TxProcessor,
+    ) -> anyhow::Result<usize> {
+        let orphans = self.orphans(newer)?;
+        let mut skipped_targets = newer.targets()?;
+        // Only the targets that still hold what was deployed are removed, the others were replaced
+        // or modified since.
+        for leaf in self.get_leaves() {
+            let Ok((source, target)) = self.parse_leaf(&leaf) else {
+                continue;
+            };
+            if skipped_targets.contains(&target) || target.symlink_metadata().is_err() {
+                continue;
+            }
+            if !utils::links_to(&target, &source) && !Self::is_unchanged(&source, &target, None)? {
+                log::warning!(
+                    "Skipping the orphan {:?} as it no longer holds the deployed {:?}",
+                    target,
+                    source
+                );
+                skipped_targets.insert(target);
+            }
+        }
+        let num_removed = orphans
+            .iter()
+            .filter(|orphan| !skipped_targets.contains(*orphan))
+            .count();
+        let mut old = self.with_skipped_targets(skipped_targets);
+        old.remove_targets(tx_proc)?;
+        if newer.is_deployed() {
+            old.set_deployed(None)?;
         }
-        txb.build("SoftDeploy")
-            .and_then(|tx| tx_proc.run_required(tx))
+        Ok(num_removed)
     }
 
-    pub fn hard_deploy(
+    /// Fails with a report of all the inaccessible directories if the targets cannot be created in
+    /// them, e.g., the system directories that require elevated permissions.
+    fn check_permissions(leaves: &[ParsedLeaf]) -> anyhow::Result<()> {
+        let mut inaccessible: BTreeMap<&Path, usize> = BTreeMap::new();
+        for (_, _, abs_target) in leaves {
+            // The missing directories are created under their nearest existing ancestor.
+            let Some(existing_dir) = abs_target
+                .ancestors()
+                .skip(1)
+                .find(|ancestor| ancestor.symlink_metadata().is_ok())
+            else {
+                continue;
+            };
+            if !utils::is_writable(existing_dir) {
+                *inaccessible.entry(existing_dir).or_default() += 1;
+            }
+        }
+        let Some((first_dir, _)) = inaccessible.first_key_value() else {
+            return Ok(());
+        };
+        let report = inaccessible
+            .iter()
+            .map(|(dir, num_targets)| format!(" - {:?} ({} targets)", dir, num_targets))
+            .join("\n");
+        Err(anyhow::Error::new(DullError::PermissionDenied(
+            first_dir.to_path_buf(),
+        )))
+            .context(format!(
+                "the targets under the following directories cannot be written, deploy them with elevated permissions, e.g., `sudo dull deploy --under <DIR>`:\n{}",
+                report
+            ))
+            .context(Failure::Precondition)
+    }
+
+    /// Replaces the deployment of the `old` virtual system with this one. Only the targets of `old`
+    /// that this virtual system does not already link are removed, and the leaves of the modules
+    /// without a mode are deployed in `default_mode`.
+    pub fn relink(
         self,
-        ignore_filenames: &[&str],
+        old: VirtualSystem<Undeployable>,
+        default_mode: DeployMode,
         tx_proc: &mut TxProcessor,
     ) -> anyhow::Result<()> {
-        let mut txb = TxBuilder::empty();
-        let fs = ActualFilesystem;
-        let leaves = self.get_leaves();
-        for leaf in leaves {
-            let (source, target) = self
-                .parse_leaf(&leaf)
-                .context(format!("could not parse the leaf {:?}", leaf))?;
-            // Traverse through the regular files indicated by the leaf.
-            let inner = WalkDir::new(&source)
+        // The copies are still replaced, as this virtual system may deploy them in a different mode.
+        let linked_targets = self
+            .diff()?
+            .into_iter()
+            .filter(|(target, state)| *state == LinkState::Linked && target.is_symlink())
+            .map(|(target, _)| target)
+            .collect();
+        let mut old = old.with_skipped_targets(linked_targets);
+        old.remove_targets(tx_proc)?;
+        let is_same_build = old.path.canonicalize().ok() == self.path.canonicalize().ok();
+        let deployable = self.prepare_deployment(tx_proc)?;
+        let ignore_filenames = deployable.ignore_filenames();
+        deployable.deploy(default_mode, &ignore_filenames, tx_proc)?;
+        if is_same_build {
+            return Ok(());
+        }
+        old.set_deployed(None)
+    }
+}
+
+impl VirtualSystem<Deployable> {
+    /// Plans linking the target to the source, unless it is already linked correctly. The link is
+    /// relative if `relative` is set and the paths share more than the root.
+    fn plan_soft_leaf(
+        unlink_txb: &mut TxBuilder,
+        txb: &mut TxBuilder,
+        source: PathBuf,
+        target: PathBuf,
+        relative: bool,
+    ) {
+        let original = if relative {
+            utils::relative_link(&target, &source).unwrap_or_else(|| {
+                log::warning!(
+                    "Linking {:?} to {:?} through the absolute path, as they only share the root",
+                    target,
+                    source
+                );
+                source.clone()
+            })
+        } else {
+            source
+        };
+        match target.read_link() {
+            // Already linked correctly, nothing to do.
+            Ok(linked) if linked == original => return,
+            // Linked to a different path, remove the old link first.
+            Ok(_) => unlink_txb.remove_file(&target),
+            Err(_) => {}
+        }
+        txb.link(original, target);
+    }
+
+    /// Plans copying the source to the target, or hard linking its regular files if `hard_link` is set.
+    /// Files and symlinks that already exist at the target are skipped if they are unchanged, and
+    /// replaced otherwise. A symlink at the target, e.g., from a soft deployment, is replaced as a whole.
+    #[allow(clippy::too_many_arguments)]
+    fn plan_hard_leaf<F: Filesystem>(
+        unlink_txb: &mut TxBuilder,
+        txb: &mut TxBuilder,
+        source: PathBuf,
+        target: PathBuf,
+        source_hash: Option<&FileHash>,
+        ignore_filenames: &[String],
+        hard_link: bool,
+        fs: &F,
+    ) -> anyhow::Result<()> {
+        if let Some((link, resolved)) = utils::find_symlink_cycle(&source) {
+            return Err(DullError::SymlinkCycle {
+                path: source,
+                link,
+                resolved,
+            }
+            .into());
+        }
+        let target_parent = target
+            .parent()
+            .context(format!("could not get the parent of {:?}", target))?;
+        let replaces_link = fs.is_symlink(&target) && !source.is_symlink();
+        if replaces_link {
+            unlink_txb.remove_file(&target);
+        }
+        // Copy the directories as a whole, leaving out their ignored contents. Only the ignored
+        // names that occur in the directory are recorded, so that the plain copies stay simple.
+        let target_exists = fs.exists(&target) && !replaces_link;
+        if source.is_dir() && !hard_link && !target_exists {
+            let present_ignored = WalkDir::new(&source)
                 .follow_root_links(true)
                 .follow_links(false)
+                .min_depth(1)
                 .into_iter()
                 .flatten()
-                .map(|p| p.path().to_path_buf())
-                // Only consider regular files or symlinks.
-                .filter(|p| p.is_symlink() || p.is_file())
-                // Make sure that the files are not in the ignored filenames list.
-                .filter(|p| {
-                    p.file_name()
-                        .map(|file_name| file_name.to_string_lossy())
-                        .map(|file_name| !ignore_filenames.contains(&file_name.as_ref()))
-                        .unwrap_or(false)
-                })
-                // Always start from the shortest path (stable sort is important)
-                .sorted_by_key(|p| p.components().count());
-            for inner_source in inner {
-                let inner_target = if inner_source == source {
-                    target.clone()
-                } else {
-                    target.join(inner_source.strip_prefix(&source).unwrap())
-                };
-                // Create the directories leading to the inner target.
-                let inner_target_parent = inner_target
-                    .parent()
-                    .context(format!("could not get the parent of {:?}", inner_target))?;
-                txb.ensure_dirs(inner_target_parent, &fs)?;
-                // Copy the file in.
+                .filter(|p| utils::is_ignored(p.path(), ignore_filenames))
+                .flat_map(|p| Some(p.path().file_name()?.to_string_lossy().to_string()))
+                .unique()
+                .collect_vec();
+            txb.ensure_dirs(target_parent, fs)?;
+            txb.copy_dir(source, target, &present_ignored);
+            return Ok(());
+        }
+        // Traverse through the regular files indicated by the leaf, pruning the ignored directories.
+        let inner = WalkDir::new(&source)
+            .follow_root_links(true)
+            .follow_links(false)
+            .into_iter()
+            .filter_entry(|entry| {
+                entry.depth() == 0 || !utils::is_ignored(entry.path(), ignore_filenames)
+            })
+            .flatten()
+            .map(|p| p.path().to_path_buf())
+            // Only consider regular files or symlinks.
+            .filter(|p| p.is_symlink() || p.is_file())
+            // Make sure that the files are not in the ignored filenames list.
+            .filter(|p| p.file_name().is_some() && !utils::is_ignored(p, ignore_filenames))
+            // Always start from the shortest path (stable sort is important)
+            .sorted_by_key(|p| p.components().count());
+        for inner_source in inner {
+            let inner_target = if inner_source == source {
+                target.clone()
+            } else {
+                target.join(inner_source.strip_prefix(&source).unwrap())
+            };
+            // Create the directories leading to the inner target.
+            let inner_target_parent = inner_target
+                .parent()
+                .context(format!("could not get the parent of {:?}", inner_target))?;
+            if replaces_link {
+                // The paths under the replaced symlink lead into the source, so none of them exist yet.
+                txb.ensure_dirs(target_parent, fs)?;
+                let new_dirs = inner_target_parent
+                    .ancestors()
+                    .take_while(|dir| dir.starts_with(&target))
+                    .map(|dir| dir.to_path_buf())
+                    .collect_vec();
+                for dir in new_dirs.into_iter().rev() {
+                    if !txb.will_create_dir(&dir) {
+                        txb.create_dir(dir);
+                    }
+                }
+            } else {
+                txb.ensure_dirs(inner_target_parent, fs)?;
+                // Skip the unchanged files and replace the changed ones.
+                let inner_source_hash = source_hash.filter(|_| inner_source == source);
+                if Self::is_unchanged(&inner_source, &inner_target, inner_source_hash)? {
+                    continue;
+                }
+                if fs.is_symlink(&inner_target) || fs.is_file(&inner_target) {
+                    unlink_txb.remove_file(&inner_target);
+                }
+            }
+            // Copy the file in, symlinks are always copied.
+            if hard_link && !inner_source.is_symlink() {
+                txb.hard_link(inner_source, inner_target);
+            } else {
                 txb.copy_file(inner_source, inner_target);
             }
         }
-        txb.build("HardDeploy")
-            .and_then(|tx| tx_proc.run_required(tx))
+        Ok(())
+    }
+
+    /// Warns if the given transaction might not fit into the target or the backup filesystem.
+    fn check_disk_space(&self, tx: &Transaction, tx_proc: &TxProcessor) {
+        if tx_proc.is_simulating() {
+            return;
+        }
+        let needs = [
+            (&self.target_root, tx.estimated_copy_bytes()),
+            (tx.backup_dir(), tx.estimated_backup_bytes()),
+        ];
+        for (path, needed) in needs {
+            if needed == 0 {
+                continue;
+            }
+            match utils::available_space(path) {
+                // Leave some headroom, as the estimate ignores the filesystem overhead.
+                Ok(available) if needed > available / 10 * 9 => log::warning!(
+                    "Transaction {} needs about {} bytes but only {} bytes are available at {:?}",
+                    tx.name(),
+                    needed,
+                    available,
+                    path
+                ),
+                Ok(_) => {}
+                Err(err) => log::debug!("Could not check the available space: {:?}", err),
+            }
+        }
+    }
+
+    /// Plans deploying the leaves in the modes of their modules without running anything. The
+    /// leaves of the modules without a mode are deployed in `default_mode`. Returns the transactions
+    /// that should be run in order: removing the outdated targets, if there are any, and then
+    /// deploying the leaves.
+    pub fn plan_deploy(
+        &self,
+        default_mode: DeployMode,
+        ignore_filenames: &[String],
+    ) -> anyhow::Result<Vec<Transaction>> {
+        let modes = self
+            .manifest
+            .as_ref()
+            .map(|manifest| manifest.modes())
+            .unwrap_or_default();
+        let source_hashes = self
+            .manifest
+            .as_ref()
+            .map(|manifest| manifest.source_hashes())
+            .unwrap_or_default();
+        let permissions = self
+            .manifest
+            .as_ref()
+            .map(|manifest| manifest.permissions())
+            .unwrap_or_default();
+        let mut unlink_txb = TxBuilder::empty();
+        let mut txb = TxBuilder::empty();
+        txb.set_dir_modes(self.dir_modes()?);
+        let fs = ActualFilesystem;
+        // The broken leaves were already reported during the preparation.
+        let (leaves, _) = self.partition_leaves();
+        for (leaf, source, target) in leaves {
+            let virtual_target = leaf.strip_prefix(&self.path).unwrap_or(&leaf);
+            let mut mode = modes.get(virtual_target).copied().unwrap_or(default_mode);
+            // A link to another device breaks when the device is unmounted, e.g., a removable drive.
+            if mode == DeployMode::Soft && utils::device_of(&source) != utils::device_of(&target) {
+                if self.copy_cross_device {
+                    log::info!("Copying {:?} as it is on a different device", source);
+                    mode = DeployMode::Hard;
+                } else if !utils::links_to(&target, &source) {
+                    log::warning!(
+                        "The link {:?} leads to {:?} on a different device, which breaks if the device is unmounted",
+                        target,
+                        source
+                    );
+                }
+            }
+            // The mode is applied after the copy is in place. The links and the hard links share the
+            // mode of their sources, which must be left as is.
+            match permissions.get(virtual_target) {
+                Some(permission) if mode == DeployMode::Hard => txb.set_mode(&target, *permission),
+                Some(_) => log::warning!(
+                    "Ignoring the permissions of {:?} as it is linked rather than copied",
+                    target
+                ),
+                None => {}
+            }
+            match mode {
+                DeployMode::Soft => Self::plan_soft_leaf(
+                    &mut unlink_txb,
+                    &mut txb,
+                    source,
+                    target,
+                    self.relative_links,
+                ),
+                mode @ (DeployMode::Hard | DeployMode::HardLink) => Self::plan_hard_leaf(
+                    &mut unlink_txb,
+                    &mut txb,
+                    source,
+                    target,
+                    source_hashes.get(virtual_target),
+                    ignore_filenames,
+                    mode == DeployMode::HardLink,
+                    &fs,
+                )?,
+            }
+        }
+        let mut txs = vec![];
+        if unlink_txb.len() > 0 {
+            txs.push(unlink_txb.build("Unlink")?);
+        }
+        txs.push(txb.build("Deploy")?);
+        Ok(txs)
+    }
+
+    /// Deploys the leaves in the modes of their modules. The leaves of the modules without a mode
+    /// are deployed in `default_mode`.
+    pub fn deploy(
+        self,
+        default_mode: DeployMode,
+        ignore_filenames: &[String],
+        tx_proc: &mut TxProcessor,
+    ) -> anyhow::Result<()> {
+        for tx in self.plan_deploy(default_mode, ignore_filenames)? {
+            self.check_disk_space(&tx, tx_proc);
+            tx_proc.run_required(tx)?;
+        }
+        // Nothing was deployed if the processor only simulated the transactions.
+        if tx_proc.is_simulating() {
+            return Ok(());
+        }
+        self.record_transactions(tx_proc)?;
+        self.set_deployed(Some(default_mode))
     }
 }